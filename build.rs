@@ -3,6 +3,35 @@ use std::io::{BufRead, Write};
 
 fn main() {
     update_readme();
+    embed_lblib();
+}
+
+/// Generates a list of every file in lblib/ that is compiled into lb, see src/lblib.rs
+fn embed_lblib() {
+    println!("cargo:rerun-if-changed=lblib");
+    let mut files = std::fs::read_dir("lblib")
+        .expect("Failed to read lblib/")
+        .map(|entry| entry.expect("Failed to read a file in lblib/").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lb"))
+        .collect::<Vec<std::path::PathBuf>>();
+    files.sort();
+
+    let entries = files
+        .iter()
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            let absolute = std::fs::canonicalize(path).expect("Failed to get path to lblib file");
+            println!("cargo:rerun-if-changed={}", path.display());
+            format!("    (\"lblib/{}\", include_str!({:?})),\n", name, absolute)
+        })
+        .collect::<String>();
+
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR is not set");
+    std::fs::write(
+        std::path::Path::new(&out_dir).join("lblib.rs"),
+        format!("pub const FILES: &[(&str, &str)] = &[\n{}];\n", entries),
+    )
+    .expect("Failed to write lblib.rs");
 }
 
 fn update_readme() {
@@ -0,0 +1,386 @@
+/*
+ * localhost-build is an experimental build scripting language
+ * Copyright (C) 2021  Carl Erik Patrik Iwarson
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::cache::{ArtifactEntry, Cache};
+use crate::fs::{copy_symlink, fingerprint, DirectoryTree, PathFilter, SymlinkPolicy};
+use crate::hash::HashAlgorithm;
+use crate::str;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where the outputs of `:cached-e` are stored, relative to the directory lb was started in
+pub const STORE_DIR: &str = ".lb-cache";
+pub const DEFAULT_LIMIT: u64 = 1024 * 1024 * 1024;
+
+/// `:cached-e --inputs <paths> --outputs <paths> -- <command>`
+#[derive(Debug, PartialEq)]
+pub struct CachedCommand {
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub command: String,
+}
+
+impl CachedCommand {
+    /// `--inputs` and `--outputs` can be repeated and take space separated paths or patterns
+    /// like `:inputs` and `:outputs`, `--` separates them from the command
+    pub fn parse(input: String, op: &str) -> CachedCommand {
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        let mut list: Option<&mut Vec<String>> = None;
+        let mut rest = input.trim_start();
+        let command = loop {
+            if let Some(r) = strip_option(rest, "--inputs") {
+                list = Some(&mut inputs);
+                rest = r;
+            } else if let Some(r) = strip_option(rest, "--outputs") {
+                list = Some(&mut outputs);
+                rest = r;
+            } else if let Some(r) = strip_option(rest, "--") {
+                break r;
+            } else {
+                let list = list
+                    .as_mut()
+                    .unwrap_or_else(|| panic!("expected --inputs or --outputs in {}", op));
+                let (value, r) = first_value(rest);
+                let value = value
+                    .unwrap_or_else(|| panic!("{} needs -- between the paths and the command", op));
+                list.push(value);
+                rest = r.trim_start();
+            }
+        };
+
+        if outputs.is_empty() {
+            panic!("{} needs at least one path in --outputs", op);
+        }
+        if command.is_empty() {
+            panic!("{} needs a command to run", op);
+        }
+        CachedCommand {
+            inputs,
+            outputs,
+            command: command.to_string(),
+        }
+    }
+
+    /// The inputs, the command and the directory it runs in decide what is restored
+    pub fn key(&self, algorithm: HashAlgorithm) -> u128 {
+        let mut hasher = algorithm.hasher();
+        hasher.update(&fingerprint(&self.inputs, &self.outputs, algorithm).to_le_bytes());
+        hasher.update(self.command.as_bytes());
+        hasher.update(&[0]);
+        if let Ok(dir) = std::env::current_dir() {
+            hasher.update(dir.to_string_lossy().as_bytes());
+        }
+        hasher.finish()
+    }
+}
+
+/// The rest of the input if it starts with the option, `None` otherwise
+fn strip_option<'a>(input: &'a str, option: &str) -> Option<&'a str> {
+    let rest = input.strip_prefix(option)?;
+    if rest.is_empty() || rest.starts_with([' ', '\t']) {
+        Some(rest.trim_start())
+    } else {
+        None
+    }
+}
+
+/// Splits off an optionally quoted value
+fn first_value(input: &str) -> (Option<String>, &str) {
+    let end = if let Some(quoted) = input.strip_prefix('"') {
+        quoted.find('"').map(|i| i + 2).unwrap_or(input.len())
+    } else {
+        input.find([' ', '\t']).unwrap_or(input.len())
+    };
+    let (value, rest) = input.split_at(end);
+    (
+        str::get_path_strings(value.to_string()).into_iter().next(),
+        rest,
+    )
+}
+
+/// Parses a size like `500M`, suffixes are K, M and G (powers of 1024)
+pub fn parse_size(input: &str, op: &str) -> u64 {
+    let input = input.trim();
+    let (number, multiplier) = match input.chars().last() {
+        Some('K') | Some('k') => (&input[..input.len() - 1], 1024),
+        Some('M') | Some('m') => (&input[..input.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&input[..input.len() - 1], 1024 * 1024 * 1024),
+        _ => (input, 1),
+    };
+    number
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .unwrap_or_else(|_| panic!("expected a size like 500M in {}, got '{}'", op, input))
+}
+
+/// The outputs of `:cached-e` commands, one directory per key with the outputs numbered in
+/// the order they were declared. What is stored is tracked in `build.lb.cache`
+pub struct ArtifactStore {
+    dir: PathBuf,
+    pub limit: u64,
+}
+
+impl ArtifactStore {
+    pub fn new(root: &Path) -> ArtifactStore {
+        ArtifactStore {
+            dir: root.join(STORE_DIR),
+            limit: DEFAULT_LIMIT,
+        }
+    }
+
+    fn entry_dir(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    /// Replaces the outputs with the stored ones, false if nothing has been stored for the key
+    pub fn restore(&self, key: u128, outputs: &[String], cache: &mut Cache) -> bool {
+        let key = format!("{:032x}", key);
+        let entry_dir = self.entry_dir(&key);
+        let entry = match cache.artifacts.get_mut(&key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        if !(0..outputs.len()).all(|i| entry_dir.join(i.to_string()).exists()) {
+            // removed by someone else, run the command again
+            cache.artifacts.remove(&key);
+            return false;
+        }
+
+        entry.last_used = now();
+        for (i, output) in outputs.iter().enumerate() {
+            let output = Path::new(output);
+            remove_any(output).unwrap_or_else(|err| {
+                panic!(
+                    "failed to remove '{:?}' before restoring it:\n{}",
+                    output, err
+                )
+            });
+            if let Some(parent) = output.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent).unwrap_or_else(|err| {
+                    panic!("failed to create directory '{:?}':\n{}", parent, err)
+                });
+            }
+            copy_any(&entry_dir.join(i.to_string()), output)
+                .unwrap_or_else(|err| panic!("failed to restore '{:?}':\n{}", output, err));
+        }
+        true
+    }
+
+    /// Copies the outputs into the store, then evicts the least recently used entries until
+    /// the store fits in the limit
+    pub fn store(&self, key: u128, outputs: &[String], cache: &mut Cache) {
+        let key = format!("{:032x}", key);
+        let entry_dir = self.entry_dir(&key);
+        let _ = fs::remove_dir_all(&entry_dir);
+
+        let mut size = 0;
+        for (i, output) in outputs.iter().enumerate() {
+            match copy_all(Path::new(output), &entry_dir.join(i.to_string())) {
+                Ok(bytes) => size += bytes,
+                Err(err) => {
+                    println!(
+                        "Warning: not caching outputs, failed to store '{}':\n{}",
+                        output, err
+                    );
+                    let _ = fs::remove_dir_all(&entry_dir);
+                    return;
+                }
+            }
+        }
+        if size > self.limit {
+            println!(
+                "Warning: not caching outputs, {} bytes is more than the limit of {} bytes",
+                size, self.limit
+            );
+            let _ = fs::remove_dir_all(&entry_dir);
+            return;
+        }
+
+        cache.artifacts.insert(
+            key.clone(),
+            ArtifactEntry {
+                size,
+                last_used: now(),
+            },
+        );
+        self.evict(cache, &key);
+    }
+
+    fn evict(&self, cache: &mut Cache, keep: &str) {
+        // directories the cache doesn't know about, e.g. after changing :cachehash
+        if let Ok(entries) = fs::read_dir(&self.dir) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if !cache.artifacts.contains_key(&name) {
+                    let _ = remove_any(&entry.path());
+                }
+            }
+        }
+
+        let mut total: u64 = cache.artifacts.values().map(|a| a.size).sum();
+        let mut by_age = cache
+            .artifacts
+            .iter()
+            .filter(|(key, _)| *key != keep)
+            .map(|(key, entry)| (entry.last_used, key.clone()))
+            .collect::<Vec<(u64, String)>>();
+        by_age.sort();
+        for (_, key) in by_age {
+            if total <= self.limit {
+                break;
+            }
+            let _ = fs::remove_dir_all(self.entry_dir(&key));
+            total -= cache.artifacts.remove(&key).map(|a| a.size).unwrap_or(0);
+        }
+    }
+
+    pub fn clear(&self) {
+        remove_any(&self.dir)
+            .unwrap_or_else(|err| panic!("failed to remove {:?}:\n{}", self.dir, err));
+    }
+}
+
+fn copy_all(source: &Path, target: &Path) -> std::io::Result<u64> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    copy_any(source, target)
+}
+
+/// Copies a file, or a directory recursively with links copied as links, returns the number
+/// of bytes copied
+fn copy_any(source: &Path, target: &Path) -> std::io::Result<u64> {
+    if !source.is_dir() {
+        return fs::copy(source, target);
+    }
+    fs::create_dir_all(target)?;
+    let mut bytes = 0;
+    let tree = DirectoryTree::filtered(
+        source.to_path_buf(),
+        PathFilter::default(),
+        SymlinkPolicy::Link,
+    );
+    for entry in tree {
+        let path = entry?.path();
+        let relative = path.strip_prefix(source).unwrap_or(&path);
+        let target = target.join(relative);
+        if path.is_symlink() {
+            copy_symlink(&path, &target)?;
+        } else if path.is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            bytes += fs::copy(&path, &target)?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Removes a file or a directory, missing paths are fine
+fn remove_any(path: &Path) -> std::io::Result<()> {
+    let result = if path.is_dir() && !path.is_symlink() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match result {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    #[test]
+    pub fn cached_command_is_parsed() {
+        let command = CachedCommand::parse(
+            r#"--inputs src Cargo.toml --outputs target/app --inputs "my dir/*.rs" -- cargo build --release"#.into(),
+            ":cached-e",
+        );
+        assert_eq!(command.inputs, ["src", "Cargo.toml", "my dir/*.rs"]);
+        assert_eq!(command.outputs, ["target/app"]);
+        assert_eq!(command.command, "cargo build --release");
+    }
+
+    #[test]
+    #[should_panic(expected = "needs -- between the paths and the command")]
+    pub fn cached_command_needs_a_separator() {
+        CachedCommand::parse("--outputs target/app cargo build".into(), ":cached-e");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn links_in_outputs_are_copied_as_links() {
+        let root = ScratchDir::new("artifacts-links");
+        fs::create_dir_all(root.join("out/sub")).unwrap();
+        fs::write(root.join("out/sub/file.txt"), "content").unwrap();
+        std::os::unix::fs::symlink("..", root.join("out/sub/loop")).unwrap();
+
+        let bytes = copy_any(&root.join("out"), &root.join("copy")).unwrap();
+        assert_eq!(bytes, 7);
+        assert!(root.join("copy/sub/file.txt").is_file());
+        assert!(root.join("copy/sub/loop").is_symlink());
+    }
+
+    #[test]
+    pub fn sizes_are_parsed() {
+        assert_eq!(parse_size("100", ""), 100);
+        assert_eq!(parse_size("2K", ""), 2048);
+        assert_eq!(parse_size("3M", ""), 3 * 1024 * 1024);
+        assert_eq!(parse_size("1g", ""), 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    pub fn least_recently_used_entries_are_evicted() {
+        let root = ScratchDir::new("artifacts");
+        let output = root.join("out.txt").to_string_lossy().to_string();
+        let outputs = [output.clone()];
+        let mut store = ArtifactStore::new(&root);
+        store.limit = 10;
+        let mut cache = Cache::default();
+
+        fs::write(&output, "first").unwrap();
+        store.store(1, &outputs, &mut cache);
+        cache
+            .artifacts
+            .get_mut(&format!("{:032x}", 1))
+            .unwrap()
+            .last_used = 0;
+        fs::write(&output, "second").unwrap();
+        store.store(2, &outputs, &mut cache);
+
+        assert!(!store.restore(1, &outputs, &mut cache));
+        fs::write(&output, "changed").unwrap();
+        assert!(store.restore(2, &outputs, &mut cache));
+        assert_eq!(fs::read_to_string(&output).unwrap(), "second");
+        assert_eq!(fs::read_dir(root.join(STORE_DIR)).unwrap().count(), 1);
+
+        fs::write(&output, "way too large").unwrap();
+        store.store(3, &outputs, &mut cache);
+        assert!(!cache.artifacts.contains_key(&format!("{:032x}", 3)));
+    }
+}
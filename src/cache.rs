@@ -0,0 +1,462 @@
+/*
+ * localhost-build is an experimental build scripting language
+ * Copyright (C) 2021  Carl Erik Patrik Iwarson
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::hash::HashAlgorithm;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Size and modification time (nanoseconds since the unix epoch) of a file,
+/// if neither has changed the content is assumed to be the same without hashing it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileState {
+    pub size: u64,
+    pub modified: u64,
+}
+
+impl FileState {
+    pub fn of(path: &Path) -> Option<FileState> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Some(FileState {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+/// What a file looked like when it was last copied by `:cpc`, `:cpdc` or `:sync`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyEntry {
+    /// Hash of the content, same for the source and the target
+    pub hash: u128,
+    pub source: FileState,
+    pub target: FileState,
+}
+
+/// Outputs of a `:cached-e` command in the artifact store
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArtifactEntry {
+    pub size: u64,
+    /// Seconds since the unix epoch, the least recently used entries are evicted first
+    pub last_used: u64,
+}
+
+/// Contents of `build.lb.cache`
+#[derive(Debug, Default, Clone)]
+pub struct Cache {
+    /// Keyed on (source, target)
+    pub copies: HashMap<(String, String), CopyEntry>,
+    /// Keyed on the phase (`@build`) or group (`!build`) that declared the inputs
+    pub fingerprints: HashMap<String, u128>,
+    /// Keyed on the hash of the inputs and command line of `:cached-e`
+    pub artifacts: HashMap<String, ArtifactEntry>,
+    /// Every hash in the cache is made with this
+    pub algorithm: HashAlgorithm,
+}
+
+/// Start of the first line of the cache, followed by the version and the hash algorithm
+const HEADER: &str = "lb-cache";
+/// Bumped whenever the format changes
+const VERSION: &str = "3";
+const COPY: &str = "copy";
+const INPUTS: &str = "inputs";
+const ARTIFACT: &str = "artifact";
+
+impl Cache {
+    pub fn is_empty(&self) -> bool {
+        self.copies.is_empty() && self.fingerprints.is_empty() && self.artifacts.is_empty()
+    }
+
+    /// Forgets everything but the hash algorithm
+    pub fn clear(&mut self) {
+        *self = Cache {
+            algorithm: self.algorithm,
+            ..Default::default()
+        };
+    }
+
+    /// Hashes made with another algorithm can't be compared, so changing it empties the cache
+    pub fn set_algorithm(&mut self, algorithm: HashAlgorithm) {
+        if self.algorithm != algorithm {
+            *self = Cache {
+                algorithm,
+                ..Default::default()
+            };
+        }
+    }
+
+    pub fn hash_file(&self, path: &Path) -> Option<u128> {
+        self.algorithm.hash_file(path).ok()
+    }
+
+    /// True if the target still has the content the source had when it was last copied, and
+    /// the source has not changed since then
+    pub fn is_copy_unchanged(&mut self, source: &Path, target: &Path) -> bool {
+        let algorithm = self.algorithm;
+        let key = Self::copy_key(source, target);
+        let entry = match self.copies.get_mut(&key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let (source_state, target_state) = match (FileState::of(source), FileState::of(target)) {
+            (Some(source), Some(target)) => (source, target),
+            _ => return false,
+        };
+
+        // the target might have been changed by something else than lb
+        if target_state != entry.target {
+            if algorithm.hash_file(target).ok() != Some(entry.hash) {
+                return false;
+            }
+            entry.target = target_state;
+        }
+        if source_state != entry.source {
+            // touched or changed, only the content matters
+            if algorithm.hash_file(source).ok() != Some(entry.hash) {
+                return false;
+            }
+            entry.source = source_state;
+        }
+        true
+    }
+
+    /// Remembers a copy that was just made
+    pub fn insert_copy(&mut self, source: &Path, target: &Path) {
+        if let (Some(source_state), Some(target_state), Some(hash)) = (
+            FileState::of(source),
+            FileState::of(target),
+            self.hash_file(target),
+        ) {
+            self.copies.insert(
+                Self::copy_key(source, target),
+                CopyEntry {
+                    hash,
+                    source: source_state,
+                    target: target_state,
+                },
+            );
+        }
+    }
+
+    /// Forgets every copy made to `target`, e.g. after removing it
+    pub fn remove_target(&mut self, target: &Path) {
+        let target = target.to_string_lossy();
+        self.copies.retain(|(_, t), _| *t != target);
+    }
+
+    fn copy_key(source: &Path, target: &Path) -> (String, String) {
+        (
+            source.to_string_lossy().to_string(),
+            target.to_string_lossy().to_string(),
+        )
+    }
+
+    /// Reads the cache at `path`, a missing file is an empty cache
+    pub fn load(path: &str) -> Cache {
+        match fs::read_to_string(path) {
+            Ok(content) => Self::parse(&content),
+            Err(_) => Cache::default(),
+        }
+    }
+
+    /// Entries that can't be read are discarded with a warning, they are simply rebuilt
+    pub fn parse(content: &str) -> Cache {
+        let mut cache = Cache::default();
+        let mut lines = content.lines();
+        let header = match lines.next() {
+            None => return cache,
+            Some(header) => header.split(' ').collect::<Vec<&str>>(),
+        };
+        match header.as_slice() {
+            [HEADER, VERSION, algorithm] => match HashAlgorithm::parse(algorithm) {
+                Some(algorithm) => cache.algorithm = algorithm,
+                None => {
+                    println!(
+                        "Warning: discarding the cache since it uses the unknown hash '{}'",
+                        algorithm
+                    );
+                    return cache;
+                }
+            },
+            _ => {
+                println!(
+                    "Warning: discarding the cache since it was written by another version of lb"
+                );
+                return cache;
+            }
+        }
+
+        for (i, line) in lines.enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            if cache.parse_entry(line).is_none() {
+                // + 2 for the header and since lines are numbered from 1
+                println!("Warning: discarding invalid cache entry on line {}", i + 2);
+            }
+        }
+        cache
+    }
+
+    fn parse_entry(&mut self, line: &str) -> Option<()> {
+        let fields = line
+            .split('\t')
+            .map(unescape)
+            .collect::<Option<Vec<String>>>()?;
+        match fields.as_slice() {
+            [kind, source, target, hash, source_size, source_modified, target_size, target_modified]
+                if kind == COPY =>
+            {
+                let entry = CopyEntry {
+                    hash: hash.parse().ok()?,
+                    source: FileState {
+                        size: source_size.parse().ok()?,
+                        modified: source_modified.parse().ok()?,
+                    },
+                    target: FileState {
+                        size: target_size.parse().ok()?,
+                        modified: target_modified.parse().ok()?,
+                    },
+                };
+                self.copies.insert((source.clone(), target.clone()), entry);
+            }
+            [kind, key, fingerprint] if kind == INPUTS => {
+                self.fingerprints
+                    .insert(key.clone(), fingerprint.parse().ok()?);
+            }
+            [kind, key, size, last_used] if kind == ARTIFACT => {
+                let entry = ArtifactEntry {
+                    size: size.parse().ok()?,
+                    last_used: last_used.parse().ok()?,
+                };
+                self.artifacts.insert(key.clone(), entry);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    pub fn serialize(&self) -> String {
+        let copies = self.copies.iter().map(|((source, target), entry)| {
+            [
+                COPY.to_string(),
+                escape(source),
+                escape(target),
+                entry.hash.to_string(),
+                entry.source.size.to_string(),
+                entry.source.modified.to_string(),
+                entry.target.size.to_string(),
+                entry.target.modified.to_string(),
+            ]
+            .join("\t")
+        });
+        let fingerprints = self.fingerprints.iter().map(|(key, fingerprint)| {
+            [INPUTS.to_string(), escape(key), fingerprint.to_string()].join("\t")
+        });
+        let artifacts = self.artifacts.iter().map(|(key, entry)| {
+            [
+                ARTIFACT.to_string(),
+                escape(key),
+                entry.size.to_string(),
+                entry.last_used.to_string(),
+            ]
+            .join("\t")
+        });
+        let header = format!("{} {} {}", HEADER, VERSION, self.algorithm.name());
+        std::iter::once(header)
+            .chain(copies)
+            .chain(fingerprints)
+            .chain(artifacts)
+            .collect::<Vec<String>>()
+            .join("\n")
+            + "\n"
+    }
+
+    /// Writes to a temporary file first so an interrupted write can't leave a broken cache
+    pub fn write(&self, path: &str) {
+        let temp_path = format!("{}.tmp", path);
+        fs::write(&temp_path, self.serialize())
+            .unwrap_or_else(|err| panic!("Failed to save {}:\n{}", temp_path, err));
+        fs::rename(&temp_path, path)
+            .unwrap_or_else(|err| panic!("Failed to save {}:\n{}", path, err));
+    }
+}
+
+/// Removes the cache at `path`, returns false if there was none
+pub fn clear(path: &str) -> bool {
+    match fs::remove_file(path) {
+        Ok(()) => true,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+        Err(err) => panic!("failed to remove {}:\n{}", path, err),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', r"\\")
+        .replace('\t', r"\t")
+        .replace('\n', r"\n")
+        .replace('\r', r"\r")
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => result.push('\\'),
+            't' => result.push('\t'),
+            'n' => result.push('\n'),
+            'r' => result.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    macro_rules! test_cache (
+        ($name:ident, $path:expr) => {
+            #[test]
+            pub fn $name() {
+                let mut cache = Cache::default();
+                let entry = CopyEntry {
+                    hash: 2,
+                    source: FileState { size: 3, modified: 4 },
+                    target: FileState { size: 5, modified: 6 },
+                };
+                cache.copies.insert(($path.to_string(), "target".to_string()), entry.clone());
+                cache.fingerprints.insert($path.to_string(), 7);
+
+                let parsed = Cache::parse(&cache.serialize());
+                assert_eq!(parsed.copies.get(&($path.to_string(), "target".to_string())), Some(&entry));
+                assert_eq!(parsed.fingerprints.get($path), Some(&7));
+            }
+        }
+    );
+
+    test_cache!(cache_backslashes, r#"\\?\C:\Program Dreams"#);
+    test_cache!(
+        cache_backslashes_and_apostrophe,
+        r#"\\?\C:\Program Dream's"#
+    );
+    test_cache!(cache_quotes, r#"C:/"quoted"/file"#);
+    test_cache!(cache_forward_slashes, r#"C:/Program Size (x51)/qemu/file"#);
+    test_cache!(cache_unix, r#"/home/someone/thing"#);
+    test_cache!(cache_tabs_and_newlines, "/home/someone/a\tb\nc");
+
+    #[test]
+    pub fn entries_from_older_versions_are_dropped() {
+        let cache = Cache::parse(r#""bin/test.txt" "4294967295""#);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    pub fn invalid_entries_are_discarded() {
+        let content = format!(
+            "{} {} crc32\ninputs\t@build\t12\ninputs\t@test\tnot-a-number\ncopy\ta\tb\ninputs\tbad\\escape\t1\n",
+            HEADER, VERSION
+        );
+        let cache = Cache::parse(&content);
+        assert_eq!(cache.fingerprints.len(), 1);
+        assert_eq!(cache.fingerprints.get("@build"), Some(&12));
+        assert!(cache.copies.is_empty());
+    }
+
+    #[test]
+    pub fn write_replaces_the_cache() {
+        let dir = ScratchDir::new("cache-write");
+        let path = dir.join_string("build.lb.cache");
+        fs::write(&path, "garbage").unwrap();
+
+        let mut cache = Cache::default();
+        cache.fingerprints.insert("@build".into(), 1);
+        cache.write(&path);
+
+        assert_eq!(Cache::load(&path).fingerprints.get("@build"), Some(&1));
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        assert!(clear(&path));
+        assert!(!clear(&path));
+        assert!(Cache::load(&path).is_empty());
+    }
+
+    #[test]
+    pub fn algorithm_is_kept_and_changing_it_empties_the_cache() {
+        let mut cache = Cache::default();
+        cache.set_algorithm(HashAlgorithm::Xxh64);
+        cache
+            .fingerprints
+            .insert("@build".into(), u64::MAX as u128 + 1);
+
+        let parsed = Cache::parse(&cache.serialize());
+        assert_eq!(parsed.algorithm, HashAlgorithm::Xxh64);
+        assert_eq!(
+            parsed.fingerprints.get("@build"),
+            Some(&(u64::MAX as u128 + 1))
+        );
+
+        cache.set_algorithm(HashAlgorithm::Xxh64);
+        assert!(!cache.is_empty());
+        cache.set_algorithm(HashAlgorithm::Fnv1a128);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    pub fn copy_is_keyed_on_source_and_target() {
+        let dir = ScratchDir::new("cache-copy");
+        let (source1, source2, target) =
+            (dir.join("source1"), dir.join("source2"), dir.join("target"));
+        fs::write(&source1, "one").unwrap();
+        fs::write(&source2, "two").unwrap();
+        fs::write(&target, "one").unwrap();
+        let mut cache = Cache::default();
+        cache.insert_copy(&source1, &target);
+
+        assert!(cache.is_copy_unchanged(&source1, &target));
+        assert!(!cache.is_copy_unchanged(&source2, &target));
+    }
+
+    #[test]
+    pub fn changed_target_is_detected() {
+        let dir = ScratchDir::new("cache-tamper");
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        fs::write(&source, "content").unwrap();
+        fs::write(&target, "content").unwrap();
+        let mut cache = Cache::default();
+        cache.insert_copy(&source, &target);
+
+        fs::write(&target, "tampered").unwrap();
+        assert!(!cache.is_copy_unchanged(&source, &target));
+
+        // same content written again is still unchanged
+        fs::write(&target, "content").unwrap();
+        assert!(cache.is_copy_unchanged(&source, &target));
+    }
+}
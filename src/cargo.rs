@@ -0,0 +1,382 @@
+/*
+ * localhost-build is an experimental build scripting language
+ * Copyright (C) 2021  Carl Erik Patrik Iwarson
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::fs;
+use crate::json::Json;
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "Cargo.toml";
+
+/// The package names of the members of the workspace in `dir`, in the order they are listed.
+/// A package that isn't a workspace is its only member
+pub fn workspace_members(dir: &Path) -> Vec<String> {
+    let manifest = read_manifest(dir);
+    let mut members = Vec::new();
+    if let Some(name) = value(&manifest, "package", "name") {
+        members.push(unquote(&name));
+    }
+
+    let excluded = value(&manifest, "workspace", "exclude")
+        .map(|exclude| strings(&exclude))
+        .unwrap_or_default();
+    let patterns = value(&manifest, "workspace", "members")
+        .map(|members| strings(&members))
+        .unwrap_or_default();
+    for pattern in patterns {
+        for member in member_dirs(dir, &pattern) {
+            let relative = member
+                .strip_prefix(dir)
+                .unwrap_or(&member)
+                .to_string_lossy()
+                .replace('\\', "/");
+            if excluded
+                .iter()
+                .any(|exclude| fs::glob_match(exclude.trim_end_matches('/'), &relative))
+            {
+                continue;
+            }
+            let name = value(&read_manifest(&member), "package", "name")
+                .unwrap_or_else(|| panic!("no package name in {:?}", member.join(MANIFEST)));
+            let name = unquote(&name);
+            if !members.contains(&name) {
+                members.push(name);
+            }
+        }
+    }
+
+    if members.is_empty() {
+        panic!("no packages in {:?}", dir.join(MANIFEST));
+    }
+    members
+}
+
+/// An error or warning from `cargo --message-format=json`
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub level: String,
+    /// `file:line:column` of the primary span, empty if there is none
+    pub location: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.level.starts_with("error")
+    }
+}
+
+/// The errors and warnings in cargo's JSON messages, other lines are ignored
+pub fn diagnostics(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for line in output.lines().filter(|line| line.starts_with('{')) {
+        let json = match Json::parse(line) {
+            Some(json) => json,
+            None => continue,
+        };
+        if json.get("reason").and_then(Json::as_str) != Some("compiler-message") {
+            continue;
+        }
+        let message = match json.get("message") {
+            Some(message) => message,
+            None => continue,
+        };
+        let level = message.get("level").and_then(Json::as_str).unwrap_or("");
+        if !level.starts_with("error") && level != "warning" {
+            continue;
+        }
+        let text = message.get("message").and_then(Json::as_str).unwrap_or("");
+        let spans = message.get("spans").map(Json::as_array).unwrap_or(&[]);
+        if spans.is_empty() && is_summary(text) {
+            continue;
+        }
+        let location = spans
+            .iter()
+            .find(|span| span.get("is_primary").and_then(Json::as_bool) == Some(true))
+            .map(|span| {
+                format!(
+                    "{}:{}:{}",
+                    span.get("file_name").and_then(Json::as_str).unwrap_or(""),
+                    span.get("line_start").and_then(Json::as_u64).unwrap_or(0),
+                    span.get("column_start").and_then(Json::as_u64).unwrap_or(0)
+                )
+            })
+            .unwrap_or_default();
+        diagnostics.push(Diagnostic {
+            level: level.to_string(),
+            location,
+            message: text.lines().next().unwrap_or("").to_string(),
+        });
+    }
+    diagnostics
+}
+
+/// "aborting due to 2 previous errors" and "3 warnings emitted" repeat what was already reported
+fn is_summary(message: &str) -> bool {
+    message.starts_with("aborting due to")
+        || message.ends_with("warning emitted")
+        || message.ends_with("warnings emitted")
+}
+
+/// The results of every test binary in the output of `cargo test`
+#[derive(Debug, Default, PartialEq)]
+pub struct TestSummary {
+    pub passed: u64,
+    pub failed: u64,
+    pub ignored: u64,
+    pub failing: Vec<String>,
+}
+
+/// Adds up the `test result:` lines and collects the tests that `FAILED`
+pub fn test_summary(output: &str) -> TestSummary {
+    let mut summary = TestSummary::default();
+    for line in strip_ansi(output).lines() {
+        if let Some(result) = line.strip_prefix("test result: ") {
+            for count in result.split(['.', ';']) {
+                let mut words = count.split_whitespace();
+                let number = words.next().and_then(|n| n.parse::<u64>().ok());
+                match (number, words.next()) {
+                    (Some(n), Some("passed")) => summary.passed += n,
+                    (Some(n), Some("failed")) => summary.failed += n,
+                    (Some(n), Some("ignored")) => summary.ignored += n,
+                    _ => {}
+                }
+            }
+        } else if let Some(test) = line.strip_prefix("test ") {
+            if let Some((name, "FAILED")) = test.rsplit_once(" ... ") {
+                summary.failing.push(name.to_string());
+            }
+        }
+    }
+    summary
+}
+
+/// Removes the color codes that `--color=always` adds
+fn strip_ansi(output: &str) -> String {
+    let mut stripped = String::with_capacity(output.len());
+    let mut chars = output.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // ESC [ parameters final-letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+fn read_manifest(dir: &Path) -> String {
+    let path = dir.join(MANIFEST);
+    std::fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("failed to read {:?}:\n{}", path, err))
+}
+
+fn member_dirs(dir: &Path, pattern: &str) -> Vec<PathBuf> {
+    let path = dir.join(pattern.trim_end_matches('/'));
+    let mut dirs = if fs::is_glob(&path.to_string_lossy()) {
+        fs::glob(&path.to_string_lossy())
+    } else {
+        vec![path]
+    };
+    dirs.retain(|dir| dir.join(MANIFEST).is_file());
+    dirs
+}
+
+/// The raw value of `key` in `[section]`, arrays can span several lines
+fn value(toml: &str, section: &str, key: &str) -> Option<String> {
+    let mut current = String::new();
+    let mut lines = toml.lines().map(without_comment);
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if line.starts_with('[') && line.ends_with(']') {
+            current = line
+                .trim_matches(|c| c == '[' || c == ']')
+                .trim()
+                .to_string();
+            continue;
+        }
+        if current != section {
+            continue;
+        }
+        let mut value = match line.split_once('=') {
+            Some((name, value)) if name.trim() == key => value.trim().to_string(),
+            _ => continue,
+        };
+        if value.starts_with('[') {
+            while !value.ends_with(']') {
+                match lines.next() {
+                    Some(next) => value.push_str(next.trim()),
+                    None => panic!("unterminated array for {} in [{}]", key, section),
+                }
+            }
+        }
+        return Some(value);
+    }
+    None
+}
+
+/// Strips a `#` comment that isn't in a string
+fn without_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            ('"', None) | ('\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            ('#', None) => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// The strings in an array like `["a", 'b']`
+fn strings(array: &str) -> Vec<String> {
+    array
+        .trim_matches(|c| c == '[' || c == ']')
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(unquote)
+        .collect()
+}
+
+fn unquote(value: &str) -> String {
+    value
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    fn write_package(dir: &Path, name: &str) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(
+            dir.join(MANIFEST),
+            format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    pub fn workspace_members_are_found() {
+        let dir = ScratchDir::new("cargo-workspace");
+        write_package(&dir.join("crates/core"), "app-core");
+        write_package(&dir.join("crates/cli"), "app-cli");
+        write_package(&dir.join("crates/old"), "app-old");
+        write_package(&dir.join("xtask"), "xtask");
+        let workspace = r#"
+[workspace]
+# the binaries are in crates/
+members = [
+    "xtask",
+    "crates/*", # every crate
+]
+exclude = ["crates/old"]
+
+[workspace.dependencies]
+name = "not a package"
+"#;
+        std::fs::write(dir.join(MANIFEST), workspace).unwrap();
+
+        assert_eq!(workspace_members(&dir), ["xtask", "app-cli", "app-core"]);
+
+        let root_package = format!("[package]\nname = \"root\"\n{}", workspace);
+        std::fs::write(dir.join(MANIFEST), root_package).unwrap();
+        assert_eq!(
+            workspace_members(&dir),
+            ["root", "xtask", "app-cli", "app-core"]
+        );
+    }
+
+    #[test]
+    pub fn diagnostics_are_parsed_from_json_messages() {
+        let output = r#"{"reason":"compiler-artifact","package_id":"app 0.1.0","fresh":true}
+{"reason":"compiler-message","package_id":"app 0.1.0","message":{"rendered":"warning: unused variable: `x`","children":[],"code":{"code":"unused_variables","explanation":null},"level":"warning","message":"unused variable: `x`","spans":[{"byte_end":30,"byte_start":29,"column_end":10,"column_start":9,"file_name":"src/main.rs","is_primary":true,"line_end":2,"line_start":2,"label":null}]}}
+{"reason":"compiler-message","package_id":"app 0.1.0","message":{"rendered":"error[E0308]: mismatched types","children":[],"code":{"code":"E0308","explanation":"..."},"level":"error","message":"mismatched types\nexpected `u32`","spans":[{"column_start":5,"file_name":"src/lib.rs","is_primary":false,"line_start":1},{"column_start":18,"file_name":"src/lib.rs","is_primary":true,"line_start":3}]}}
+{"reason":"compiler-message","package_id":"app 0.1.0","message":{"level":"error","message":"aborting due to 1 previous error","spans":[]}}
+{"reason":"compiler-message","package_id":"app 0.1.0","message":{"level":"warning","message":"1 warning emitted","spans":[]}}
+{"reason":"build-finished","success":false}
+error: could not compile `app`"#;
+        assert_eq!(
+            diagnostics(output),
+            [
+                Diagnostic {
+                    level: "warning".into(),
+                    location: "src/main.rs:2:9".into(),
+                    message: "unused variable: `x`".into(),
+                },
+                Diagnostic {
+                    level: "error".into(),
+                    location: "src/lib.rs:3:18".into(),
+                    message: "mismatched types".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_results_are_added_up() {
+        let output = "
+running 3 tests
+test tests::adds ... ok
+test tests::\u{1b}[1msubtracts\u{1b}[0m ... \u{1b}[31mFAILED\u{1b}[0m
+test tests::slow ... ignored, takes a minute
+
+failures:
+
+---- tests::subtracts stdout ----
+assertion failed
+
+test result: \u{1b}[31mFAILED\u{1b}[0m. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.01s
+
+running 2 tests
+test src/lib.rs - add (line 3) ... ok
+test src/lib.rs - sub (line 9) ... FAILED
+
+test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.20s
+";
+        assert_eq!(
+            test_summary(output),
+            TestSummary {
+                passed: 2,
+                failed: 2,
+                ignored: 1,
+                failing: vec![
+                    "tests::subtracts".into(),
+                    "src/lib.rs - sub (line 9)".into()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    pub fn comments_in_strings_are_kept() {
+        assert_eq!(
+            without_comment(r#"name = "a#b" # comment"#),
+            r#"name = "a#b" "#
+        );
+        assert_eq!(strings(r#"["a", 'b',]"#), ["a", "b"]);
+    }
+}
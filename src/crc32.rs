@@ -16,12 +16,17 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use crate::hash::StreamHasher;
 use crate::tuple;
+use std::sync::OnceLock;
+
+// 8 tables of 256 entries, the first is the usual byte-at-a-time table and the others let
+// Crc32Table::update process 8 bytes per step (slicing-by-8)
 tuple!(Crc32Table(table: Vec<u32>));
 
 impl Default for Crc32Table {
     fn default() -> Self {
-        (0u32..256)
+        let mut table = (0u32..256)
             .map(|i| {
                 (0..8).fold(i, |acc, _| {
                     if acc & 1 != 0 {
@@ -31,23 +36,77 @@ impl Default for Crc32Table {
                     }
                 })
             })
-            .collect::<Vec<u32>>()
-            .into()
+            .collect::<Vec<u32>>();
+        for i in 256..256 * 8 {
+            let previous = table[i - 256];
+            table.push((previous >> 8) ^ table[(previous & 0xFF) as usize]);
+        }
+        table.into()
     }
 }
 
 impl Crc32Table {
-    #[inline(always)]
-    pub fn calculate(&self, bytes: &[u8]) -> u32 {
-        bytes.iter().fold(!0u32, |acc, it| {
-            self.table[(((acc & 0xFF) as u8) ^ *it) as usize] ^ (acc >> 8)
+    /// Built once and shared by every [Crc32]
+    pub fn shared() -> &'static Crc32Table {
+        static TABLE: OnceLock<Crc32Table> = OnceLock::new();
+        TABLE.get_or_init(Crc32Table::default)
+    }
+
+    /// Continues a crc32 with more bytes, `crc` is the state before the final inversion
+    pub fn update(&self, mut crc: u32, bytes: &[u8]) -> u32 {
+        let t = &self.table;
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            let one = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) ^ crc;
+            let two = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            crc = t[7 * 256 + (one & 0xFF) as usize]
+                ^ t[6 * 256 + ((one >> 8) & 0xFF) as usize]
+                ^ t[5 * 256 + ((one >> 16) & 0xFF) as usize]
+                ^ t[4 * 256 + (one >> 24) as usize]
+                ^ t[3 * 256 + (two & 0xFF) as usize]
+                ^ t[2 * 256 + ((two >> 8) & 0xFF) as usize]
+                ^ t[256 + ((two >> 16) & 0xFF) as usize]
+                ^ t[(two >> 24) as usize];
+        }
+        chunks.remainder().iter().fold(crc, |acc, it| {
+            t[(((acc & 0xFF) as u8) ^ *it) as usize] ^ (acc >> 8)
         })
     }
+
+    pub fn calculate(&self, bytes: &[u8]) -> u32 {
+        !self.update(!0u32, bytes)
+    }
+
     pub fn compare(&self, bytes: &[u8], crc32: u32) -> bool {
         self.calculate(bytes) == crc32
     }
 }
 
+/// Streaming crc32
+pub struct Crc32 {
+    table: &'static Crc32Table,
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32 {
+            table: Crc32Table::shared(),
+            crc: !0u32,
+        }
+    }
+}
+
+impl StreamHasher for Crc32 {
+    fn update(&mut self, bytes: &[u8]) {
+        self.crc = self.table.update(self.crc, bytes);
+    }
+
+    fn finish(&self) -> u128 {
+        !self.crc as u128
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -70,4 +129,27 @@ mod tests {
 
         assert_eq!(table.calculate(&value1), table.calculate(&value2));
     }
+
+    #[test]
+    pub fn known_vectors() {
+        let table = Crc32Table::default();
+        assert_eq!(table.calculate(b""), 0);
+        assert_eq!(table.calculate(b"123456789"), 0xCBF43926);
+        assert_eq!(
+            table.calculate(b"The quick brown fox jumps over the lazy dog"),
+            0x414FA339
+        );
+    }
+
+    #[test]
+    pub fn slicing_matches_one_byte_at_a_time() {
+        let table = Crc32Table::default();
+        let bytes = (0..100u8).map(|i| i.wrapping_mul(37)).collect::<Vec<u8>>();
+        for len in 0..bytes.len() {
+            let one_at_a_time = bytes[..len]
+                .iter()
+                .fold(!0u32, |crc, b| table.update(crc, &[*b]));
+            assert_eq!(!one_at_a_time, table.calculate(&bytes[..len]));
+        }
+    }
 }
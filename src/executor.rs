@@ -15,19 +15,38 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use crate::crc32::Crc32Table;
+use crate::artifacts;
+use crate::artifacts::{ArtifactStore, CachedCommand};
+use crate::cache;
+use crate::cache::Cache;
+use crate::cargo;
+use crate::hash::HashAlgorithm;
 use crate::lexer::Lexer;
+use crate::report::{Report, ReportFormat, StepKind};
 use crate::token::*;
 use crate::{fs, preprocessor, str, table};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
 use std::thread::sleep;
+use std::time::Instant;
 
 pub enum Evaluation {
     And,
     Or,
 }
 
+/// Files declared with `:inputs` and `:outputs` in a phase or group
+struct Freshness {
+    key: String,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    checked: bool,
+    up_to_date: bool,
+    /// A process exited with an error, so the outputs can't be trusted
+    failed: bool,
+}
+
 pub struct Executor {
     lexer: Lexer,
     last_proc_out: String,
@@ -37,28 +56,54 @@ pub struct Executor {
     awaiting_evaluation: Option<Evaluation>,
     last_if_test_value: String,
     goto_phase: Option<String>,
+    current_phase: Option<String>,
+    executing_group: Option<String>,
+    phase_freshness: Option<Freshness>,
+    group_freshness: Option<Freshness>,
     variables: HashMap<String, String>,
+    cli_variables: HashSet<String>,
     groups: HashMap<String, GroupDefinition>,
     executing_group_args: HashMap<String, String>,
     announcing_phases: bool,
-    cache: HashMap<String, u32>,
-    crc_table: Crc32Table,
+    cache: Cache,
+    cache_path: String,
+    artifacts: ArtifactStore,
     table: table::Table,
+    root_dir: PathBuf,
+    last_file_stat: Option<fs::FileStat>,
+    report: Report,
+    phase_step: Option<usize>,
+    group_step: Option<usize>,
+    started: Instant,
+    timers: HashMap<String, Instant>,
 }
 
+pub const CACHE_FILE: &str = "build.lb.cache";
+
 const ARGTO: &str = ":argto";
 const AND: &str = ":and";
+const APPEND: &str = ":append";
+const ARTIFACTLIMIT: &str = ":artifactlimit";
+const CACHEDE: &str = ":cached-e";
+const CACHEHASH: &str = ":cachehash";
+const CARGODIAG: &str = ":cargodiag";
+const CARGOMEMBERS: &str = ":cargomembers";
+const CLEARCACHE: &str = ":clearcache";
 const CONTAINS: &str = ":contains";
 const CPDC: &str = ":cpdc";
 const CPC: &str = ":cpc";
 const CPD: &str = ":cpd";
 const CD: &str = ":cd";
 const CP: &str = ":cp";
+pub const EACH: &str = ":each";
 const EMPTY: &str = ":empty";
+const EXISTS: &str = ":exists";
 const ENW: &str = ":enw";
 const EP: &str = ":ep";
 const EQ: &str = ":eq";
 const E: &str = ":e";
+const FILESIZE: &str = ":filesize";
+const GLOB: &str = ":glob";
 const GOTOF: &str = ":gotof";
 const GOTOT: &str = ":gotot";
 const GOTO: &str = ":goto";
@@ -66,20 +111,26 @@ const HASARG: &str = ":hasarg";
 const HASVAR: &str = ":hasvar";
 const HV: &str = ":hv";
 const H: &str = ":h";
+const ISDIR: &str = ":isdir";
 const ISE: &str = ":ise";
+const ISFILE: &str = ":isfile";
 const ISS: &str = ":iss";
 const IF: &str = ":if";
+const INPUTS: &str = ":inputs";
 const LEO: &str = ":leo";
 const LOE: &str = ":loe";
 const LOS: &str = ":los";
 const LF: &str = ":lf";
 const LT: &str = ":lt";
 const L: &str = ":l";
+const MKDIR: &str = ":mkdir";
 const MVD: &str = ":mvd";
 const MV: &str = ":mv";
 const NEQ: &str = ":neq";
+const NEWER: &str = ":newer";
 const NOT: &str = ":not";
 const OR: &str = ":or";
+const OUTPUTS: &str = ":outputs";
 const QEF: &str = ":qef";
 const QET: &str = ":qet";
 const QOEE: &str = ":qoee";
@@ -88,20 +139,33 @@ const QE: &str = ":qe";
 const QF: &str = ":qf";
 const QT: &str = ":qt";
 const Q: &str = ":q";
+const RMD: &str = ":rmd";
+const RM: &str = ":rm";
 const SILENT: &str = ":silent";
 const SETF: &str = ":setf";
 const SETT: &str = ":sett";
 const SET: &str = ":set";
+const SYNC: &str = ":sync";
 const TB: &str = ":tb";
+const TESTSUMMARY: &str = ":testsummary";
 const TE: &str = ":te";
 const TH: &str = ":th";
+const TIMER: &str = ":timer";
+const TOUCH: &str = ":touch";
 const TR: &str = ":tr";
 const WC: &str = ":wc";
+const WRITE: &str = ":write";
 const WS: &str = ":ws";
 
+/// The processes and file system commands that are recorded for reports and `--timings`
+const RECORDED: [&str; 17] = [
+    APPEND, CACHEDE, CP, CPC, CPD, CPDC, E, ENW, EP, MKDIR, MV, MVD, RM, RMD, SYNC, TOUCH, WRITE,
+];
+
 impl Executor {
     pub fn new(script: String) -> Executor {
         let script = preprocessor::perform_imports(script);
+        let root_dir = std::env::current_dir().expect("failed to get current directory");
         let mut executor = Executor {
             lexer: Lexer::new(script.clone().into(), false),
             last_proc_out: String::new(),
@@ -111,13 +175,26 @@ impl Executor {
             awaiting_evaluation: None,
             last_if_test_value: "".into(),
             goto_phase: None,
+            current_phase: None,
+            executing_group: None,
+            phase_freshness: None,
+            group_freshness: None,
             variables: HashMap::new(),
+            cli_variables: HashSet::new(),
             groups: HashMap::new(),
             executing_group_args: HashMap::new(),
             announcing_phases: true,
-            cache: HashMap::new(),
-            crc_table: Crc32Table::default(),
+            cache: Cache::default(),
+            cache_path: CACHE_FILE.into(),
+            artifacts: ArtifactStore::new(&root_dir),
             table: Default::default(),
+            root_dir,
+            last_file_stat: None,
+            report: Report::default(),
+            phase_step: None,
+            group_step: None,
+            started: Instant::now(),
+            timers: HashMap::new(),
         };
 
         let preprocessor_lexer = Lexer::new(script.into(), true);
@@ -125,8 +202,17 @@ impl Executor {
         executor
     }
 
-    pub fn with_cache(script: String, cache: HashMap<String, u32>) -> Executor {
+    /// Variables from `lb --set name=value`, assignments in the script don't change them
+    pub fn set_cli_variables(&mut self, variables: Vec<(String, String)>) {
+        for (name, value) in variables {
+            self.cli_variables.insert(name.clone());
+            self.variables.insert(name, value);
+        }
+    }
+
+    pub fn with_cache(script: String, cache: Cache) -> Executor {
         let script = preprocessor::perform_imports(script);
+        let root_dir = std::env::current_dir().expect("failed to get current directory");
         let mut executor = Executor {
             lexer: Lexer::new(script.clone().into(), false),
             last_proc_out: String::new(),
@@ -136,13 +222,26 @@ impl Executor {
             awaiting_evaluation: None,
             last_if_test_value: "".into(),
             goto_phase: None,
+            current_phase: None,
+            executing_group: None,
+            phase_freshness: None,
+            group_freshness: None,
             variables: HashMap::new(),
+            cli_variables: HashSet::new(),
             groups: HashMap::new(),
             executing_group_args: HashMap::new(),
             announcing_phases: true,
             cache,
-            crc_table: Crc32Table::default(),
+            cache_path: CACHE_FILE.into(),
+            artifacts: ArtifactStore::new(&root_dir),
             table: Default::default(),
+            root_dir,
+            last_file_stat: None,
+            report: Report::default(),
+            phase_step: None,
+            group_step: None,
+            started: Instant::now(),
+            timers: HashMap::new(),
         };
 
         let preprocessor_lexer = Lexer::new(script.into(), true);
@@ -155,8 +254,22 @@ impl Executor {
             "stderr" => self.last_proc_err.clone(),
             "stdout" => self.last_proc_out.clone(),
             "exit-code" => self.last_proc_code.to_string(),
-            "pwd" => std::env::current_dir().unwrap().to_string_lossy().to_string(),
+            "mtime" => self
+                .last_file_stat
+                .as_ref()
+                .map(|s| s.modified.to_string())
+                .unwrap_or_default(),
+            "filesize" => self
+                .last_file_stat
+                .as_ref()
+                .map(|s| s.size.to_string())
+                .unwrap_or_default(),
+            "pwd" => std::env::current_dir()
+                .unwrap()
+                .to_string_lossy()
+                .to_string(),
             "args" => Self::get_args(),
+            "elapsed" => format!("{:.3}", self.started.elapsed().as_secs_f64()),
             c if self.executing_group_args.contains_key(c) => {
                 self.executing_group_args.get(c).unwrap().clone()
             }
@@ -258,12 +371,17 @@ impl Executor {
                             continue 'execute_loop;
                         }
                     }
+                    self.finish_phase();
+                    self.current_phase = Some(s.clone());
+                    if self.report.is_recording() {
+                        self.phase_step = Some(self.report.start(StepKind::Phase, s, None));
+                    }
                     if self.announcing_phases {
                         println!("Starting phase {}", s);
                     }
                 }
                 TokenKind::ExecuteGroup(ref s, ref args) => {
-                    if self.goto_phase.is_some() {
+                    if self.skipping_phase() || self.skip_if_up_to_date() {
                         token = self.lexer.next_token();
                         continue 'execute_loop;
                     }
@@ -277,7 +395,7 @@ impl Executor {
                     }
                 }
                 TokenKind::Command(ref s) => {
-                    if self.goto_phase.is_some() {
+                    if self.skipping_phase() {
                         token = self.lexer.next_token();
                         continue 'execute_loop;
                     }
@@ -301,6 +419,8 @@ impl Executor {
                         break 'execute_loop;
                     }
                 }
+                TokenKind::Variable(var_name, _) | TokenKind::VariableIfNotSet(var_name, _)
+                    if self.cli_variables.contains(&var_name) => {}
                 TokenKind::Variable(var_name, value) => {
                     self.variables
                         .entry(var_name)
@@ -319,7 +439,142 @@ impl Executor {
             println!("goto could not find phase '{}'", goto);
         }
 
+        self.finish_phase();
         self.write_cache();
+        if self.report.is_recording() {
+            self.report.write();
+        }
+    }
+
+    /// Quits with the exit code, the reports are still written
+    fn exit(&mut self, code: i32, command: &str) -> ! {
+        if self.report.is_recording() {
+            self.report.quit(code, command, self.current_phase.as_ref());
+            self.report.write();
+        }
+        std::process::exit(code);
+    }
+
+    pub fn set_reports(&mut self, formats: Vec<ReportFormat>) {
+        self.report.formats = formats;
+    }
+
+    /// `lb --timings`, prints how long every phase and command took at the end
+    pub fn set_timings(&mut self, timings: bool) {
+        self.report.timings = timings;
+    }
+
+    fn skipping_phase(&self) -> bool {
+        self.goto_phase.is_some() || self.phase_freshness.as_ref().is_some_and(|f| f.up_to_date)
+    }
+
+    /// Adds files to the `:inputs` or `:outputs` of the current group, or phase if not in a group
+    fn declare_files(&mut self, input: String, command: &str) {
+        let key = match (&self.executing_group, &self.current_phase) {
+            // the same group called with other arguments has other inputs and outputs
+            (Some(group), _) => self
+                .groups
+                .get(group)
+                .map(|g| &g.args)
+                .into_iter()
+                .flatten()
+                .filter_map(|arg| self.executing_group_args.get(arg))
+                .fold(format!("!{}", group), |key, arg| format!("{} {}", key, arg)),
+            (None, Some(phase)) => phase.clone(),
+            (None, None) => panic!("'{}' has to be used in a phase or group", command),
+        };
+        let freshness = if self.executing_group.is_some() {
+            &mut self.group_freshness
+        } else {
+            &mut self.phase_freshness
+        };
+        let freshness = freshness.get_or_insert_with(|| Freshness {
+            key,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            checked: false,
+            up_to_date: false,
+            failed: false,
+        });
+        if freshness.checked {
+            panic!(
+                "'{}' has to come before any other command in the phase or group",
+                command
+            );
+        }
+
+        let files = str::get_path_strings(input);
+        if command == INPUTS {
+            freshness.inputs.extend(files);
+        } else {
+            freshness.outputs.extend(files);
+        }
+    }
+
+    /// Checks the files declared in the current group or phase the first time something else
+    /// is executed in it, returns true if the rest of the group or phase should be skipped
+    fn skip_if_up_to_date(&mut self) -> bool {
+        let freshness = if self.executing_group.is_some() {
+            &mut self.group_freshness
+        } else {
+            &mut self.phase_freshness
+        };
+        let freshness = match freshness {
+            Some(freshness) if !freshness.checked => freshness,
+            _ => return false,
+        };
+        freshness.checked = true;
+
+        let fingerprint =
+            fs::fingerprint(&freshness.inputs, &freshness.outputs, self.cache.algorithm);
+        freshness.up_to_date = self.cache.fingerprints.get(&freshness.key) == Some(&fingerprint)
+            && freshness.outputs.iter().all(|o| Path::new(o).exists());
+        if freshness.up_to_date && self.announcing_phases {
+            println!("Skipping {}, up-to-date", freshness.key);
+        }
+        freshness.up_to_date
+    }
+
+    /// Sets a variable for `:set` and the like, variables set with `lb --set` are kept as is
+    fn set_variable(&mut self, name: String, value: String) {
+        if !self.cli_variables.contains(&name) {
+            self.variables.insert(name, value);
+        }
+    }
+
+    /// Remembers that a process in the current phase or group exited with an error
+    fn record_exit_code(&mut self) {
+        if self.last_proc_code == 0 {
+            return;
+        }
+        for freshness in self
+            .phase_freshness
+            .iter_mut()
+            .chain(self.group_freshness.iter_mut())
+        {
+            freshness.failed = true;
+        }
+    }
+
+    /// Stores the fingerprint of the inputs if no process in the phase or group failed
+    fn store_fingerprint(&mut self, freshness: Option<Freshness>) {
+        if let Some(freshness) = freshness {
+            if freshness.up_to_date || freshness.failed {
+                return;
+            }
+            let fingerprint =
+                fs::fingerprint(&freshness.inputs, &freshness.outputs, self.cache.algorithm);
+            self.cache.fingerprints.insert(freshness.key, fingerprint);
+        }
+    }
+
+    fn finish_phase(&mut self) {
+        let freshness = self.phase_freshness.take();
+        self.store_fingerprint(freshness);
+        self.current_phase = None;
+        if let Some(step) = self.phase_step.take() {
+            self.report.finish(step);
+        }
     }
 
     fn write_cache(&mut self) {
@@ -327,15 +582,7 @@ impl Executor {
             return;
         }
 
-        let cache = self
-            .cache
-            .iter()
-            .map(str::get_cache_line)
-            .collect::<Vec<String>>()
-            .join("\n");
-
-        std::fs::write("build.lb.cache", cache)
-            .unwrap_or_else(|_| panic!("Failed to save build.lb.cache"));
+        self.cache.write(&self.cache_path);
     }
 
     fn execute_group(&mut self, group: &GroupDefinition, args: &[String]) -> bool {
@@ -357,6 +604,15 @@ impl Executor {
             self.executing_group_args
                 .insert(arg.clone(), args[i].clone());
         }
+        self.executing_group = Some(group.name.clone());
+        if self.report.is_recording() {
+            let name = format!("!{}", group.name);
+            self.group_step = Some(self.report.start(
+                StepKind::Group,
+                &name,
+                self.current_phase.as_ref(),
+            ));
+        }
 
         for c in &group.commands {
             match c.kind {
@@ -380,16 +636,30 @@ impl Executor {
 
                     let input = String::from_iter(sb);
                     if self.execute_command(command, input) {
+                        self.finish_group();
                         return true;
                     }
+                    if self.group_freshness.as_ref().is_some_and(|f| f.up_to_date) {
+                        break;
+                    }
                 }
                 _ => unimplemented!(),
             }
         }
-        self.executing_group_args.clear();
+        self.finish_group();
         false
     }
 
+    fn finish_group(&mut self) {
+        let freshness = self.group_freshness.take();
+        self.store_fingerprint(freshness);
+        self.executing_group = None;
+        self.executing_group_args.clear();
+        if let Some(step) = self.group_step.take() {
+            self.report.finish(step);
+        }
+    }
+
     fn get_execution_args(input: String) -> (String, Vec<String>) {
         //let (process, args) = str::separate_first_value_from_rest(input, EP).destructure();
         let args = str::get_line_strings(input);
@@ -398,9 +668,75 @@ impl Executor {
         (process.to_owned(), iter.collect())
     }
 
+    /// Runs `input` like `:e`, keeping its output and exit code
+    fn execute_process(&mut self, input: String) {
+        let input_clone = input.clone();
+        let (process, args) = Self::get_execution_args(input);
+
+        //println!("process: {:?}, args: {:?}", process, args);
+        let result = std::process::Command::new(&process)
+            .args(&args)
+            .output()
+            .unwrap_or_else(|err| {
+                panic!(
+                    "process failed to execute process '{}' with args '{:#?}':\n{}",
+                    &process, &args, err
+                )
+            });
+
+        self.last_proc_err = String::from_utf8(result.stderr).expect("stderr was not UTF-8");
+        self.last_proc_out = String::from_utf8(result.stdout).expect("stdout was not UTF-8");
+        self.last_proc_code = result.status.code().unwrap_or_else(|| {
+            panic!(
+                "failed to retrieve exit code from process when running :e {}",
+                input_clone
+            )
+        });
+        self.record_exit_code();
+    }
+
     /// return value is "should_quit"
     fn execute_command(&mut self, command: &str, input: String) -> bool {
+        if command != INPUTS && command != OUTPUTS && self.skip_if_up_to_date() {
+            return false;
+        }
+        if !self.report.is_recording() || !RECORDED.contains(&command) {
+            return self.run_command(command, input);
+        }
+
+        let name = format!(
+            "{} {}",
+            command,
+            input.split_whitespace().collect::<Vec<&str>>().join(" ")
+        );
+        let step = self.report.start(
+            StepKind::Command,
+            name.trim_end(),
+            self.current_phase.as_ref(),
+        );
+        let should_quit = self.run_command(command, input);
         match command {
+            E | CACHEDE => self.report.finish_command(
+                step,
+                self.last_proc_code,
+                &self.last_proc_out,
+                &self.last_proc_err,
+            ),
+            // the output went straight to the terminal
+            EP => self
+                .report
+                .finish_command(step, self.last_proc_code, "", ""),
+            _ => self.report.finish(step),
+        }
+        should_quit
+    }
+
+    fn run_command(&mut self, command: &str, input: String) -> bool {
+        match command {
+            APPEND => {
+                let (path, content) = fs::get_path_and_content(input, APPEND);
+                fs::write_file(&path, &content, true);
+            }
             ARGTO => {
                 let strings = str::get_line_strings(input);
                 let mut strings = strings.into_iter();
@@ -411,10 +747,7 @@ impl Executor {
                     .next()
                     .unwrap_or_else(|| panic!("'{}' requires a variable to set (arg 2)", ARGTO));
                 let arg_value = Self::get_arg(arg);
-                self.variables
-                    .entry(variable)
-                    .and_modify(|v| *v = arg_value.clone())
-                    .or_insert_with(|| arg_value);
+                self.set_variable(variable, arg_value);
             }
             AND => {
                 self.awaiting_evaluation = Some(Evaluation::And);
@@ -422,12 +755,82 @@ impl Executor {
             CONTAINS => {
                 self.add_if_result(self.last_if_test_value.contains(&input));
             }
+            ARTIFACTLIMIT => {
+                self.artifacts.limit = artifacts::parse_size(&input, ARTIFACTLIMIT);
+            }
+            CACHEDE => {
+                let cached = CachedCommand::parse(input, CACHEDE);
+                let key = cached.key(self.cache.algorithm);
+                if self
+                    .artifacts
+                    .restore(key, &cached.outputs, &mut self.cache)
+                {
+                    if self.announcing_phases {
+                        println!(
+                            "Restored {} from {}",
+                            cached.outputs.join(", "),
+                            artifacts::STORE_DIR
+                        );
+                    }
+                    self.last_proc_out = String::new();
+                    self.last_proc_err = String::new();
+                    self.last_proc_code = 0;
+                } else {
+                    self.execute_process(cached.command);
+                    if self.last_proc_code == 0 {
+                        self.artifacts.store(key, &cached.outputs, &mut self.cache);
+                    }
+                }
+            }
+            CACHEHASH => {
+                let algorithm = HashAlgorithm::parse(&input).unwrap_or_else(|| {
+                    panic!(
+                        "unknown hash '{}' in {}, expected crc32, xxh64 or fnv128",
+                        input, CACHEHASH
+                    )
+                });
+                self.cache.set_algorithm(algorithm);
+            }
+            CARGODIAG => {
+                let diagnostics = cargo::diagnostics(&self.last_proc_out);
+                let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+                self.set_variable("errors".into(), errors.to_string());
+                self.set_variable("warnings".into(), (diagnostics.len() - errors).to_string());
+                if !diagnostics.is_empty() {
+                    let mut table = table::Table::new(2);
+                    table.set_headers(vec!["Level".into(), "Location".into(), "Message".into()]);
+                    for diagnostic in diagnostics {
+                        table.add_row(vec![
+                            diagnostic.level,
+                            diagnostic.location,
+                            diagnostic.message,
+                        ]);
+                    }
+                    table.print();
+                }
+            }
+            CARGOMEMBERS => {
+                let strings = str::get_path_strings(input);
+                let mut strings = strings.into_iter();
+                let variable = strings.next().unwrap_or_else(|| {
+                    panic!("'{}' requires a variable to set (arg 1)", CARGOMEMBERS)
+                });
+                let dir = strings.next().unwrap_or_else(|| ".".into());
+                let members = cargo::workspace_members(Path::new(&dir));
+                self.set_variable(variable, members.join(" "));
+            }
             CD => {
                 std::env::set_current_dir(&input)
                     .unwrap_or_else(|_| panic!("failed to set current dir to '{}'", input));
             }
+            CLEARCACHE => {
+                self.cache.clear();
+                cache::clear(&self.cache_path);
+                self.artifacts.clear();
+            }
             CPDC => {
-                let fs_op = fs::get_source_and_target(input, CPDC);
+                let (fs_op, options) = fs::get_source_target_and_options(input, CPDC);
+                let options = fs::CopyOptions::parse(options, CPDC);
                 if let Ok(is_dir) = std::fs::metadata(&fs_op.source).map(|m| m.is_dir()) {
                     if !is_dir {
                         panic!(
@@ -436,10 +839,15 @@ impl Executor {
                         );
                     }
                 }
-                fs::cached_copy_dir(&fs_op, &mut self.cache, &self.crc_table);
+                let mut stats = fs::CopyStats::default();
+                for fs_op in fs::expand_source(fs_op, true, options.dry_run, CPDC) {
+                    fs::cached_copy_dir(&fs_op, &options, &mut self.cache, &mut stats);
+                }
+                self.set_copy_stats(&stats);
             }
             CPC => {
-                let fs_op = fs::get_source_and_target(input, CPC);
+                let (fs_op, options) = fs::get_source_target_and_options(input, CPC);
+                let options = fs::CopyOptions::parse(options, CPC);
                 if let Ok(is_dir) = std::fs::metadata(&fs_op.source).map(|m| m.is_dir()) {
                     if is_dir {
                         panic!(
@@ -449,10 +857,15 @@ impl Executor {
                     }
                 }
 
-                fs::cached_copy(fs_op, &mut self.cache, &self.crc_table);
+                let mut stats = fs::CopyStats::default();
+                for fs_op in fs::expand_source(fs_op, false, options.dry_run, CPC) {
+                    fs::cached_copy(fs_op, &options, &mut self.cache, &mut stats);
+                }
+                self.set_copy_stats(&stats);
             }
             CPD => {
-                let fs_op = fs::get_source_and_target(input, CPD);
+                let (fs_op, options) = fs::get_source_target_and_options(input, CPD);
+                let options = fs::CopyOptions::parse(options, CPD);
                 if let Ok(is_dir) = std::fs::metadata(&fs_op.source).map(|m| m.is_dir()) {
                     if !is_dir {
                         panic!(
@@ -461,10 +874,15 @@ impl Executor {
                         );
                     }
                 }
-                fs::copy_dir(&fs_op);
+                let mut stats = fs::CopyStats::default();
+                for fs_op in fs::expand_source(fs_op, true, options.dry_run, CPD) {
+                    fs::copy_dir(&fs_op, &options, &mut stats);
+                }
+                self.set_copy_stats(&stats);
             }
             CP => {
-                let fs_op = fs::get_source_and_target(input, CP);
+                let (fs_op, options) = fs::get_source_target_and_options(input, CP);
+                let options = fs::CopyOptions::parse(options, CP);
                 if let Ok(is_dir) = std::fs::metadata(&fs_op.source).map(|m| m.is_dir()) {
                     if is_dir {
                         panic!(
@@ -473,17 +891,46 @@ impl Executor {
                         );
                     }
                 }
-                fs::copy(&fs_op);
+                let mut stats = fs::CopyStats::default();
+                for fs_op in fs::expand_source(fs_op, false, options.dry_run, CP) {
+                    fs::copy(&fs_op, &options, &mut stats);
+                }
+                self.set_copy_stats(&stats);
+            }
+            EACH => {
+                if self.executing_group.is_some() {
+                    panic!("'{}' can't be used in a group", EACH);
+                }
+                let strings = str::get_line_strings(input);
+                let mut strings = strings.into_iter();
+                let name = strings
+                    .next()
+                    .unwrap_or_else(|| panic!("'{}' requires a group to run (arg 1)", EACH));
+                let group = self
+                    .groups
+                    .get(&name)
+                    .unwrap_or_else(|| panic!("Group {} has not been defined anywhere", name))
+                    .clone();
+                for value in strings {
+                    if self.execute_group(&group, &[value]) {
+                        return true;
+                    }
+                }
             }
             EMPTY => {
                 self.add_if_result(self.last_if_test_value.is_empty());
             }
+            EXISTS => {
+                let path = self.get_file_path(input, EXISTS);
+                self.add_if_result(fs::exists(&path));
+            }
             ENW => {
                 // execute, no waiting
                 let input_clone = input.clone();
                 let (process, args) = Self::get_execution_args(input);
 
-                std::process::Command::new(process)
+                #[allow(clippy::zombie_processes)]
+                let _child = std::process::Command::new(process)
                     .args(args)
                     .spawn()
                     .unwrap_or_else(|_| panic!("process failed to execute (:enw {})", input_clone));
@@ -507,35 +954,60 @@ impl Executor {
                     })
                     .code()
                     .expect("failed to retrieve exit code from process");
+                self.record_exit_code();
             }
             EQ => {
                 self.add_if_result(self.last_if_test_value == input);
             }
             E => {
-                let input_clone = input.clone();
-                let (process, args) = Self::get_execution_args(input);
-
-                //println!("process: {:?}, args: {:?}", process, args);
-                let result = std::process::Command::new(&process)
-                    .args(&args)
-                    .output()
-                    .unwrap_or_else(|err| {
-                        panic!(
-                            "process failed to execute process '{}' with args '{:#?}':\n{}",
-                            &process, &args, err
-                        )
-                    });
-
-                self.last_proc_err =
-                    String::from_utf8(result.stderr).expect("stderr was not UTF-8");
-                self.last_proc_out =
-                    String::from_utf8(result.stdout).expect("stdout was not UTF-8");
-                self.last_proc_code = result.status.code().unwrap_or_else(|| {
+                self.execute_process(input);
+            }
+            FILESIZE => {
+                let strings = str::get_path_strings(input);
+                let (path, comparison, bytes) = match strings.as_slice() {
+                    [path, comparison, bytes] => (path, comparison, bytes),
+                    _ => panic!(
+                        "'{}' requires a path, a comparison and a number of bytes",
+                        FILESIZE
+                    ),
+                };
+                let compare: fn(&u64, &u64) -> bool = match comparison.as_str() {
+                    "=" | "==" => u64::eq,
+                    "!=" => u64::ne,
+                    "<" => u64::lt,
+                    "<=" => u64::le,
+                    ">" => u64::gt,
+                    ">=" => u64::ge,
+                    c => panic!("Unknown comparison '{}' in {}", c, FILESIZE),
+                };
+                let bytes = bytes.parse::<u64>().unwrap_or_else(|err| {
                     panic!(
-                        "failed to retrieve exit code from process when running :e {}",
-                        input_clone
+                        "Invalid number of bytes '{}' in {}:\n{}",
+                        bytes, FILESIZE, err
                     )
                 });
+                self.last_file_stat = fs::stat(path);
+                let result = self
+                    .last_file_stat
+                    .as_ref()
+                    .is_some_and(|stat| compare(&stat.size, &bytes));
+                self.add_if_result(result);
+            }
+            GLOB => {
+                let strings = str::get_path_strings(input);
+                let mut strings = strings.into_iter();
+                let pattern = strings
+                    .next()
+                    .unwrap_or_else(|| panic!("'{}' requires a pattern (arg 1)", GLOB));
+                let variable = strings
+                    .next()
+                    .unwrap_or_else(|| panic!("'{}' requires a variable to set (arg 2)", GLOB));
+                let paths = fs::glob(&pattern)
+                    .into_iter()
+                    .map(|p| format!("\"{}\"", p.to_string_lossy().replace('\\', "/")))
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                self.set_variable(variable, paths);
             }
             GOTOF => {
                 if !self.get_if_result(GOTOF) {
@@ -575,6 +1047,14 @@ impl Executor {
                 Self::print_help(false);
                 return true;
             }
+            ISDIR => {
+                let path = self.get_file_path(input, ISDIR);
+                self.add_if_result(Path::new(&path).is_dir());
+            }
+            ISFILE => {
+                let path = self.get_file_path(input, ISFILE);
+                self.add_if_result(Path::new(&path).is_file());
+            }
             ISE => {
                 if self.last_proc_code != 0 {
                     self.add_if_result(true);
@@ -592,6 +1072,9 @@ impl Executor {
             IF => {
                 self.last_if_test_value = input;
             }
+            INPUTS => {
+                self.declare_files(input, INPUTS);
+            }
             LEO => {
                 // _l_og std_e_rr std_o_ut
                 if !self.last_proc_err.is_empty() {
@@ -625,8 +1108,25 @@ impl Executor {
             L => {
                 println!("{}", input);
             }
+            MKDIR => {
+                for path in str::get_path_strings(input) {
+                    fs::make_dir(&path);
+                }
+            }
             MVD => {
-                todo!();
+                let fs_op = fs::get_source_and_target(input, MVD);
+                if let Ok(is_dir) = std::fs::metadata(&fs_op.source).map(|m| m.is_dir()) {
+                    if !is_dir {
+                        panic!(
+                            "'{}' is not a directory, use {} to move single files",
+                            &fs_op.source, MV
+                        );
+                    }
+                }
+
+                for fs_op in fs::expand_source(fs_op, true, false, MVD) {
+                    fs::move_dir(&fs_op);
+                }
             }
             MV => {
                 let fs_op = fs::get_source_and_target(input, MV);
@@ -639,11 +1139,18 @@ impl Executor {
                     }
                 }
 
-                fs::move_it(&fs_op);
+                for fs_op in fs::expand_source(fs_op, false, false, MV) {
+                    fs::move_it(&fs_op);
+                }
             }
             NEQ => {
                 self.add_if_result(self.last_if_test_value != input);
             }
+            NEWER => {
+                let fs_op = fs::get_source_and_target(input, NEWER);
+                self.last_file_stat = fs::stat(&fs_op.source);
+                self.add_if_result(fs::is_newer(&fs_op.source, &fs_op.target));
+            }
             NOT => {
                 let last_res = self.get_if_result(NOT);
                 self.add_if_result(!last_res);
@@ -651,19 +1158,22 @@ impl Executor {
             OR => {
                 self.awaiting_evaluation = Some(Evaluation::Or);
             }
+            OUTPUTS => {
+                self.declare_files(input, OUTPUTS);
+            }
             QOEE => {
                 if self.last_proc_code != 0 {
-                    std::process::exit(1);
+                    self.exit(1, QOEE);
                 }
             }
             QEF => {
                 if !self.get_if_result(QEF) {
-                    std::process::exit(1);
+                    self.exit(1, QEF);
                 }
             }
             QET => {
                 if self.get_if_result(QET) {
-                    std::process::exit(1);
+                    self.exit(1, QET);
                 }
             }
             QOE => {
@@ -671,8 +1181,26 @@ impl Executor {
                     return true;
                 }
             }
+            RMD => {
+                let paths = str::get_path_strings(input);
+                let force = paths.iter().any(|p| p == "--force");
+                for path in paths.iter().filter(|p| *p != "--force") {
+                    if fs::is_glob(path) {
+                        for dir in fs::glob(path).into_iter().filter(|p| p.is_dir()) {
+                            fs::remove_dir(&dir.to_string_lossy(), &self.root_dir, force);
+                        }
+                    } else {
+                        fs::remove_dir(path, &self.root_dir, force);
+                    }
+                }
+            }
+            RM => {
+                for path in str::get_path_strings(input) {
+                    fs::remove_files(&path);
+                }
+            }
             QE => {
-                std::process::exit(1);
+                self.exit(1, QE);
             }
             QF => {
                 if !self.get_if_result(QF) {
@@ -690,41 +1218,46 @@ impl Executor {
             SILENT => {
                 self.announcing_phases = false;
             }
+            SYNC => {
+                let (fs_op, options) = fs::get_source_target_and_options(input, SYNC);
+                let options = fs::CopyOptions::parse(options, SYNC);
+                if !Path::new(&fs_op.source).is_dir() {
+                    panic!(
+                        "'{}' is not a directory, {} only syncs directories",
+                        fs_op.source, SYNC
+                    );
+                }
+                if !options.force {
+                    fs::ensure_target_inside(&fs_op.target, &self.root_dir, "sync");
+                }
+                let mut stats = fs::CopyStats::default();
+                fs::sync_dir(&fs_op, &options, &mut self.cache, &mut stats);
+                self.set_copy_stats(&stats);
+            }
             SETF => {
                 if !self.get_if_result(SETF) {
                     let (first, rest) =
                         str::separate_first_value_from_rest(input, SETF).destructure();
-
-                    self.variables
-                        .entry(first)
-                        .and_modify(|v| *v = rest.clone())
-                        .or_insert_with(|| rest);
+                    self.set_variable(first, rest);
                 }
             }
             SETT => {
                 if self.get_if_result(SETT) {
                     let (first, rest) =
                         str::separate_first_value_from_rest(input, SETT).destructure();
-                    self.variables
-                        .entry(first)
-                        .and_modify(|v| *v = rest.clone())
-                        .or_insert_with(|| rest);
+                    self.set_variable(first, rest);
                 }
             }
             SET => {
                 let (first, rest) = str::separate_first_value_from_rest(input, SET).destructure();
-                self.variables
-                    .entry(first)
-                    .and_modify(|v| *v = rest.clone())
-                    .or_insert_with(|| rest);
+                self.set_variable(first, rest);
             }
             TB => {
                 // reset table
                 let arguments = str::get_line_strings(input);
                 let argument = arguments
                     .iter()
-                    .filter(|a| a.chars().any(|c| !c.is_ascii_whitespace()))
-                    .next();
+                    .find(|a| a.chars().any(|c| !c.is_ascii_whitespace()));
                 if let Some(arg) = argument {
                     let margin = arg.parse::<usize>();
                     match margin {
@@ -735,18 +1268,77 @@ impl Executor {
                     self.table = table::Table::new(5);
                 }
             } // table begin
+            TESTSUMMARY => {
+                let summary = cargo::test_summary(&self.last_proc_out);
+                self.set_variable("passed".into(), summary.passed.to_string());
+                self.set_variable("failed".into(), summary.failed.to_string());
+                self.set_variable("ignored".into(), summary.ignored.to_string());
+                let failing = summary
+                    .failing
+                    .iter()
+                    .map(|test| format!("\"{}\"", test))
+                    .collect::<Vec<String>>();
+                self.set_variable("failed-tests".into(), failing.join(" "));
+                if !summary.failing.is_empty() {
+                    let mut table = table::Table::new(2);
+                    table.set_headers(vec!["Failed test".into()]);
+                    for test in summary.failing {
+                        table.add_row(vec![test]);
+                    }
+                    table.print();
+                }
+            }
             TE => {
                 self.table.print();
             } // table end
             TH => {
                 self.table.set_headers(str::get_line_strings(input));
             } // table headers (headers separated by spaces or strings)
+            TIMER => {
+                let strings = str::get_line_strings(input);
+                let (action, name) = match strings.as_slice() {
+                    [action, name] => (action.as_str(), name.clone()),
+                    _ => panic!("'{}' requires start or stop and a name", TIMER),
+                };
+                match action {
+                    "start" => {
+                        self.timers.insert(name, Instant::now());
+                    }
+                    "stop" => {
+                        let started = self.timers.remove(&name).unwrap_or_else(|| {
+                            panic!(
+                                "'{} stop {}' without '{} start {}'",
+                                TIMER, name, TIMER, name
+                            )
+                        });
+                        let elapsed = started.elapsed();
+                        if self.report.is_recording() {
+                            self.report
+                                .add_timer(&name, self.current_phase.as_ref(), elapsed);
+                        }
+                        self.set_variable(
+                            format!("timer-{}", name),
+                            format!("{:.3}", elapsed.as_secs_f64()),
+                        );
+                    }
+                    _ => panic!("'{}' expects start or stop, got '{}'", TIMER, action),
+                }
+            }
+            TOUCH => {
+                for path in str::get_path_strings(input) {
+                    fs::touch(&path);
+                }
+            }
             TR => {
                 self.table.add_row(str::get_line_strings(input));
             } // table row (cells separated by spaces or strings)
             WC => {
                 self.write_cache();
             }
+            WRITE => {
+                let (path, content) = fs::get_path_and_content(input, WRITE);
+                fs::write_file(&path, &content, false);
+            }
             WS => {
                 let seconds = input
                     .parse::<u64>()
@@ -758,6 +1350,29 @@ impl Executor {
         false
     }
 
+    /// Prints the summary of a copy unless `:silent` was used and sets the copy variables
+    fn set_copy_stats(&mut self, stats: &fs::CopyStats) {
+        if self.announcing_phases {
+            println!("{}", stats.summary());
+        }
+        self.set_variable("copied-count".into(), stats.copied.to_string());
+        self.set_variable("skipped-count".into(), stats.skipped.to_string());
+        self.set_variable("failed-count".into(), stats.failed.to_string());
+        self.set_variable("copied-bytes".into(), stats.bytes.to_string());
+        self.set_variable("removed-count".into(), stats.removed.to_string());
+    }
+
+    /// Gets the path to test and remembers its modification time and size for `$mtime` and
+    /// `$filesize`
+    fn get_file_path(&mut self, input: String, command: &str) -> String {
+        let path = str::get_path_strings(input)
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| panic!("'{}' requires a path", command));
+        self.last_file_stat = fs::stat(&path);
+        path
+    }
+
     fn add_if_result(&mut self, value: bool) {
         if let Some(eval) = &self.awaiting_evaluation {
             match eval {
@@ -793,6 +1408,12 @@ impl Executor {
             println!("{:<20}Example", "Command");
         }
 
+        Self::help(
+            verbose,
+            APPEND,
+            "appends the rest of the line to a file, creating it if needed",
+            "build.log built $pwd\\n",
+        );
         Self::help(
             verbose,
             ARGTO,
@@ -814,21 +1435,56 @@ impl Executor {
         Self::help(
             verbose,
             CPDC,
-            "copies directory's files that have changed",
-            "\"C:/1\" \"C:/2\"",
+            "copies directory's files that have changed (a pattern copies the matching directories into a directory), takes the same options as :cpd",
+            "\"C:/1\" \"C:/2\" --exclude target",
         );
         Self::help(
             verbose,
             CPC,
-            "copy the specified file if it has changed since last copy",
+            "copy the specified file if it has changed since last copy (a pattern copies the matching files into a directory), takes the same options as :cp",
             "\"C:/1.txt\" \"C:/2.txt\"",
         );
-        Self::help(verbose, CPD, "copies directory", "\"C:/1\" \"C:/2\"");
+        Self::help(
+            verbose,
+            CPD,
+            "copies directory (a pattern copies the matching directories into a directory), options: --include <pattern>, --exclude <pattern>, --ignore-file <file>, --symlinks follow|link|skip and the options of :cp",
+            "\"C:/1\" \"C:/2\" --exclude .git --exclude \"*.swp\"",
+        );
         Self::help(
             verbose,
             CP,
-            "copies the specified file",
-            "\"C:/1.txt\" \"C:/2.txt\"",
+            "copies the specified file (a pattern copies the matching files into a directory), options: --preserve keeps modification times and permissions, --dry-run only prints what would be copied, sets $copied-count, $skipped-count, $failed-count and $copied-bytes",
+            "\"C:/*.txt\" \"C:/2\"",
+        );
+        Self::help(
+            verbose,
+            ARTIFACTLIMIT,
+            "sets the maximum size of the :cached-e store (default 1G), least recently used outputs are removed first",
+            "500M",
+        );
+        Self::help(
+            verbose,
+            CACHEDE,
+            "like :e, but restores the outputs from .lb-cache instead of running the command when the inputs and command line are the same as a previous successful run",
+            "--inputs src Cargo.toml --outputs target/release/app -- cargo build --release",
+        );
+        Self::help(
+            verbose,
+            CACHEHASH,
+            "sets the hash used by the cache: crc32 (default), xxh64 or fnv128 (128-bit), changing it empties the cache",
+            "xxh64",
+        );
+        Self::help(
+            verbose,
+            CARGODIAG,
+            "reads the output of :e cargo ... --message-format=json, sets $errors and $warnings and prints them in a table",
+            "",
+        );
+        Self::help(
+            verbose,
+            CARGOMEMBERS,
+            "sets a variable to the package names of the cargo workspace members, optionally in another directory",
+            "members crates/app",
         );
         Self::help(
             verbose,
@@ -836,12 +1492,30 @@ impl Executor {
             "sets the current working directory",
             "test_dir",
         );
+        Self::help(
+            verbose,
+            CLEARCACHE,
+            "forgets everything cached so far and removes the cache file and .lb-cache, all copies, phases and :cached-e commands run again",
+            "",
+        );
+        Self::help(
+            verbose,
+            EACH,
+            "runs a group once for each value, with the value as its first argument, it can't be used inside a group",
+            "test-member $members",
+        );
         Self::help(
             verbose,
             EMPTY,
             "sets last result to true if the last :if was empty",
             "",
         );
+        Self::help(
+            verbose,
+            EXISTS,
+            "sets last result to true if the path exists (or anything matches the pattern)",
+            "node_modules",
+        );
         Self::help(
             verbose,
             ENW,
@@ -861,6 +1535,18 @@ impl Executor {
             "hello",
         );
         Self::help(verbose, E, "executes process", "cargo build");
+        Self::help(
+            verbose,
+            FILESIZE,
+            "sets last result to true if the file exists and its size in bytes compares true (=, !=, <, <=, >, >=)",
+            "dist/app > 0",
+        );
+        Self::help(
+            verbose,
+            GLOB,
+            "sets a variable to the quoted paths matching the pattern, e.g. for :inputs",
+            "src/**/*.rs sources",
+        );
         Self::help(
             verbose,
             GOTOF,
@@ -888,6 +1574,18 @@ impl Executor {
         );
         Self::help(verbose, HV, "shows verbose help", "");
         Self::help(verbose, H, "shows minimal help", "");
+        Self::help(
+            verbose,
+            ISDIR,
+            "sets last result to true if the path is a directory",
+            "dist",
+        );
+        Self::help(
+            verbose,
+            ISFILE,
+            "sets last result to true if the path is a file",
+            "dist/app",
+        );
         Self::help(
             verbose,
             ISE,
@@ -906,6 +1604,12 @@ impl Executor {
             "sets the value to be evaluated by the following command",
             "$args",
         );
+        Self::help(
+            verbose,
+            INPUTS,
+            "skips the rest of the phase or group if the files have not changed since it last succeeded",
+            "src Cargo.toml",
+        );
         Self::help(verbose, LEO, "log stdout and stderr", "");
         Self::help(
             verbose,
@@ -932,16 +1636,22 @@ impl Executor {
             "was true!",
         );
         Self::help(verbose, L, "logs specified message", "hello world");
+        Self::help(
+            verbose,
+            MKDIR,
+            "creates the specified directories and their parents",
+            "dist/bin",
+        );
         Self::help(
             verbose,
             MVD,
-            "(TODO) moves the specified directory",
-            "(TODO)",
+            "moves the specified directory (a pattern moves the matching directories into a directory)",
+            "\"C:/1\" \"C:/2\"",
         );
         Self::help(
             verbose,
             MV,
-            "moves the specified file",
+            "moves the specified file (a pattern moves the matching files into a directory)",
             "\"C:/1.txt\" \"C:/2.txt\"",
         );
         Self::help(
@@ -950,6 +1660,12 @@ impl Executor {
             "compares the value in :if to the value specified in :neq (is not equal)",
             "hello",
         );
+        Self::help(
+            verbose,
+            NEWER,
+            "sets last result to true if the first file was modified after the second (or the second does not exist)",
+            "src/main.rs dist/app",
+        );
         Self::help(
             verbose,
             NOT,
@@ -962,6 +1678,12 @@ impl Executor {
             "returns true if the last result or the following result are true",
             "",
         );
+        Self::help(
+            verbose,
+            OUTPUTS,
+            "files that have to exist for the phase or group to be skipped by :inputs",
+            "target/release/app",
+        );
         Self::help(
             verbose,
             QOEE,
@@ -986,6 +1708,18 @@ impl Executor {
             "quits script with exit code 1 (error) if last :if returned true",
             "",
         );
+        Self::help(
+            verbose,
+            RMD,
+            "removes the specified directories and everything in them, --force allows directories outside of the directory lb was started in",
+            "dist",
+        );
+        Self::help(
+            verbose,
+            RM,
+            "removes the specified files (or files matching a pattern)",
+            "\"dist/*.pdb\"",
+        );
         Self::help(verbose, QF, "quits script if last :if returned false", "");
         Self::help(verbose, QT, "quits script if last :if returned true", "");
         Self::help(verbose, QE, "quits script with exit code 1 (error)", "");
@@ -1014,14 +1748,38 @@ impl Executor {
             "stops printing \"Starting phase[...]\"",
             "",
         );
+        Self::help(
+            verbose,
+            SYNC,
+            "copies directory's files that have changed and removes files and directories missing from the source, takes the same options as :cpd (--dry-run prints what would be removed), refuses to sync into the directory lb runs in or one outside of it unless --force is given, sets $removed-count",
+            "\"build/web\" \"dist\" --dry-run",
+        );
         Self::help(verbose, TB, "start a new table", "");
         Self::help(verbose, TE, "ends and prints the table", "");
+        Self::help(
+            verbose,
+            TESTSUMMARY,
+            "reads the output of :e cargo test, sets $passed, $failed, $ignored and $failed-tests (quoted) and prints the failed tests in a table",
+            "",
+        );
         Self::help(
             verbose,
             TH,
             "sets the headers of the table",
             "Header-1 Header-2",
         );
+        Self::help(
+            verbose,
+            TIMER,
+            "starts a timer, or stops it and sets $timer-<name> to the seconds it ran, $elapsed is the seconds since lb started",
+            "stop tests",
+        );
+        Self::help(
+            verbose,
+            TOUCH,
+            "creates the specified files or updates their modification time",
+            "dist/.stamp",
+        );
         Self::help(verbose, TR, "adds a row to the table", "Value-1 Value-2");
         Self::help(
             verbose,
@@ -1029,6 +1787,12 @@ impl Executor {
             "writes the cache before continuing, otherwise cache is written at normal script exit",
             "",
         );
+        Self::help(
+            verbose,
+            WRITE,
+            "writes the rest of the line to a file, replacing its content",
+            "version.txt $version",
+        );
         Self::help(verbose, WS, "waits seconds", "1");
     }
 
@@ -1047,6 +1811,7 @@ impl Executor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::ScratchDir;
 
     #[test]
     pub fn groups_are_not_added_as_commands_to_script() {
@@ -1122,15 +1887,442 @@ mod tests {
         executor.execute();
     }
 
+    fn run_with_cache(script: &str, cache: Cache, cache_path: &str) -> Executor {
+        let mut executor = Executor::with_cache(script.into(), cache);
+        executor.cache_path = cache_path.into();
+        executor.execute();
+        executor
+    }
+
+    #[test]
+    pub fn phase_with_unchanged_inputs_is_skipped() {
+        let dir = ScratchDir::new("inputs-phase");
+        let input = dir.join_string("input.txt");
+        let cache_path = dir.join_string("build.lb.cache");
+        std::fs::write(&input, "1").unwrap();
+        let script = format!(
+            r#"
+        @build
+        :inputs "{}"
+        :set ran yes
+        @after
+        :set after yes
+        "#,
+            input
+        );
+
+        let first = run_with_cache(&script, Cache::default(), &cache_path);
+        assert_eq!(first.variables.get("ran").unwrap(), "yes");
+        assert!(first.cache.fingerprints.contains_key("@build"));
+
+        let second = run_with_cache(&script, first.cache.clone(), &cache_path);
+        assert!(!second.variables.contains_key("ran"));
+        assert_eq!(second.variables.get("after").unwrap(), "yes");
+
+        std::fs::write(&input, "2").unwrap();
+        let third = run_with_cache(&script, second.cache.clone(), &cache_path);
+        assert_eq!(third.variables.get("ran").unwrap(), "yes");
+    }
+
+    #[test]
+    pub fn group_with_missing_outputs_is_not_skipped() {
+        let dir = ScratchDir::new("inputs-group");
+        let input = dir.join_string("input.txt");
+        let output = dir.join_string("output.txt");
+        let cache_path = dir.join_string("build.lb.cache");
+        std::fs::write(&input, "1").unwrap();
+        let script = format!(
+            r#"
+        @build
+        !gen
+        [gen
+            :inputs "{}"
+            :outputs "{}"
+            :set ran yes
+        ]
+        "#,
+            input, output
+        );
+
+        let first = run_with_cache(&script, Cache::default(), &cache_path);
+        assert_eq!(first.variables.get("ran").unwrap(), "yes");
+        assert!(first.cache.fingerprints.contains_key("!gen"));
+
+        let second = run_with_cache(&script, first.cache.clone(), &cache_path);
+        assert_eq!(second.variables.get("ran").unwrap(), "yes");
+
+        std::fs::write(&output, "generated").unwrap();
+        let third = run_with_cache(&script, second.cache.clone(), &cache_path);
+        assert!(!third.variables.contains_key("ran"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn group_called_with_other_arguments_is_not_skipped() {
+        let dir = ScratchDir::new("inputs-args");
+        let first_input = dir.join_string("input-1.txt");
+        let second_input = dir.join_string("input-2.txt");
+        let log = dir.join_string("runs.log");
+        let cache_path = dir.join_string("build.lb.cache");
+        std::fs::write(&first_input, "1").unwrap();
+        std::fs::write(&second_input, "2").unwrap();
+        let script = format!(
+            r#"
+        @build
+        !gen "{0}"
+        !gen "{1}"
+        [gen $input
+            :inputs "$input"
+            :e sh -c "echo $input >> {2}"
+        ]
+        "#,
+            first_input, second_input, log
+        );
+
+        let first = run_with_cache(&script, Cache::default(), &cache_path);
+        assert_eq!(std::fs::read_to_string(&log).unwrap().lines().count(), 2);
+
+        std::fs::write(&second_input, "3").unwrap();
+        run_with_cache(&script, first.cache.clone(), &cache_path);
+        let log_content = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(log_content.lines().count(), 3);
+        assert_eq!(log_content.lines().last(), Some(second_input.as_str()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn phase_with_an_earlier_failure_is_not_up_to_date() {
+        let dir = ScratchDir::new("inputs-failure");
+        let input = dir.join_string("input.txt");
+        let cache_path = dir.join_string("build.lb.cache");
+        std::fs::write(&input, "1").unwrap();
+        let script = format!(
+            r#"
+        @build
+        :inputs "{}"
+        :e sh -c "exit 1"
+        :e sh -c "exit 0"
+        "#,
+            input
+        );
+
+        let executor = run_with_cache(&script, Cache::default(), &cache_path);
+        assert_eq!(executor.last_proc_code, 0);
+        assert!(!executor.cache.fingerprints.contains_key("@build"));
+    }
+
+    #[test]
+    pub fn cli_variables_are_not_changed_by_the_script() {
+        let script = "
+        $check-type ?= clippy
+        $profile = debug
+        $color = always
+        :set profile dev
+        :if yes
+        :eq yes
+        :sett check-type clippy
+        :if yes
+        :eq no
+        :setf profile test
+        :argto -x check-type
+        ";
+        let mut executor = Executor::new(script.into());
+        executor.set_cli_variables(vec![
+            ("check-type".into(), "check".into()),
+            ("profile".into(), "release".into()),
+        ]);
+        executor.execute();
+        assert_eq!(executor.variables.get("check-type").unwrap(), "check");
+        assert_eq!(executor.variables.get("profile").unwrap(), "release");
+        assert_eq!(executor.variables.get("color").unwrap(), "always");
+    }
+
+    #[test]
+    pub fn command_results_do_not_change_cli_variables() {
+        let script = "
+        :glob src/*.rs sources
+        :cp src/*.none out --dry-run
+        :cargomembers members
+        :cargodiag
+        :testsummary
+        ";
+        let mut executor = Executor::new(script.into());
+        executor.set_cli_variables(vec![
+            ("sources".into(), "src/main.rs".into()),
+            ("copied-count".into(), "1".into()),
+            ("members".into(), "app".into()),
+            ("errors".into(), "3".into()),
+            ("passed".into(), "7".into()),
+        ]);
+        executor.execute();
+        assert_eq!(executor.variables.get("sources").unwrap(), "src/main.rs");
+        assert_eq!(executor.variables.get("copied-count").unwrap(), "1");
+        assert_eq!(executor.variables.get("skipped-count").unwrap(), "0");
+        assert_eq!(executor.variables.get("members").unwrap(), "app");
+        assert_eq!(executor.variables.get("errors").unwrap(), "3");
+        assert_eq!(executor.variables.get("warnings").unwrap(), "0");
+        assert_eq!(executor.variables.get("passed").unwrap(), "7");
+        assert_eq!(executor.variables.get("failed").unwrap(), "0");
+    }
+
+    #[test]
+    pub fn timers_set_a_variable_and_are_recorded() {
+        let script = "
+        $wait = mine
+        @build
+        :timer start wait
+        :ws 0
+        :timer stop wait
+        :set total $elapsed
+        ";
+        let mut executor = Executor::new(script.into());
+        executor.set_timings(true);
+        executor.execute();
+        assert_eq!(executor.variables.get("wait").unwrap(), "mine");
+        let wait = executor.variables.get("timer-wait").unwrap();
+        assert!(wait.parse::<f64>().is_ok(), "{}", wait);
+        let total = executor.variables.get("total").unwrap();
+        assert!(total.parse::<f64>().unwrap() >= wait.parse::<f64>().unwrap());
+        let steps = executor
+            .report
+            .steps
+            .iter()
+            .map(|step| (step.kind, step.name.as_str()))
+            .collect::<Vec<(StepKind, &str)>>();
+        assert_eq!(
+            steps,
+            [(StepKind::Phase, "@build"), (StepKind::Timer, "wait")]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn processes_file_commands_and_groups_are_recorded_for_reports() {
+        let root = ScratchDir::new("recorded");
+        let dir = root.join_string("made");
+        let script = format!(
+            r#"
+        @build
+        !make "{}"
+        :l done
+        [make $dir
+            :mkdir "$dir"
+            :e sh -c "exit 3"
+            :ep sh -c "echo printed; exit 4"
+        ]
+        "#,
+            dir
+        );
+        let mut executor = Executor::new(script);
+        executor.set_timings(true);
+        executor.execute();
+        let steps = executor
+            .report
+            .steps
+            .iter()
+            .map(|step| (step.kind, step.name.clone(), step.exit_code))
+            .collect::<Vec<(StepKind, String, Option<i32>)>>();
+        assert_eq!(
+            steps,
+            [
+                (StepKind::Phase, "@build".into(), None),
+                (StepKind::Group, "!make".into(), None),
+                (StepKind::Command, format!(":mkdir \"{}\"", dir), None),
+                (StepKind::Command, ":e sh -c \"exit 3\"".into(), Some(3)),
+                (
+                    StepKind::Command,
+                    ":ep sh -c \"echo printed; exit 4\"".into(),
+                    Some(4)
+                ),
+            ]
+        );
+        assert!(executor.report.steps[4].stdout.is_empty());
+        assert!(executor
+            .report
+            .junit()
+            .contains(r#"<failure message="exit code 4"/>"#));
+    }
+
+    /// Syncs an empty directory into `target` with `root` as the directory lb runs in
+    fn sync_with_root(name: &str, target: &str) {
+        let root = ScratchDir::new(name);
+        let source = root.join_string("source");
+        std::fs::create_dir_all(&source).unwrap();
+        let target = target.replace("<root>", &root.to_string_lossy());
+        let script = format!(r#":sync "{}" "{}""#, source, target);
+        let mut executor = Executor::new(script);
+        executor.root_dir = root.to_path_buf();
+        executor.execute();
+    }
+
+    #[test]
+    pub fn sync_inside_of_the_root_does_not_require_force() {
+        sync_with_root("sync-inside", "<root>/out");
+    }
+
+    #[test]
+    #[should_panic(expected = "refusing to sync")]
+    pub fn sync_into_the_root_requires_force() {
+        sync_with_root("sync-into-root", "<root>/source/..");
+    }
+
+    #[test]
+    #[should_panic(expected = "refusing to sync")]
+    pub fn sync_outside_of_the_root_requires_force_before_the_target_exists() {
+        sync_with_root("sync-outside", "<root>/../lb-sync-outside/out");
+    }
+
+    #[test]
+    pub fn file_predicates() {
+        let dir = ScratchDir::new("predicates");
+        let file = dir.join_string("file.txt");
+        std::fs::write(&file, "hello").unwrap();
+        let script = format!(
+            r#"
+        :exists "{file}"
+        :sett exists yes
+        :isdir "{file}"
+        :setf not-dir yes
+        :filesize "{file}" > 3
+        :sett size $filesize
+        :exists "{file}.missing"
+        :setf missing yes
+        "#,
+            file = file
+        );
+
+        let mut executor = Executor::new(script);
+        executor.execute();
+        assert_eq!(executor.variables.get("exists").unwrap(), "yes");
+        assert_eq!(executor.variables.get("not-dir").unwrap(), "yes");
+        assert_eq!(executor.variables.get("size").unwrap(), "5");
+        assert_eq!(executor.variables.get("missing").unwrap(), "yes");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown comparison '=>'")]
+    pub fn file_size_checks_the_comparison_of_missing_files() {
+        let script = r#"
+        :filesize "missing.txt" => 3
+        "#;
+        let mut executor = Executor::new(script.into());
+        executor.execute();
+    }
+
+    #[test]
+    pub fn cached_directory_copy_sets_copy_stats() {
+        let dir = ScratchDir::new("copy-stats");
+        let source = dir.join_string("source");
+        let target = dir.join_string("target");
+        let cache_path = dir.join_string("build.lb.cache");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(format!("{}/a.txt", source), "aaa").unwrap();
+        std::fs::write(format!("{}/b.txt", source), "bb").unwrap();
+        let script = |options: &str| {
+            format!(
+                r#"
+        :cpdc "{}" "{}" {}
+        "#,
+                source, target, options
+            )
+        };
+
+        let dry_run = run_with_cache(&script("--dry-run"), Cache::default(), &cache_path);
+        assert_eq!(dry_run.variables.get("copied-count").unwrap(), "2");
+        assert_eq!(dry_run.variables.get("copied-bytes").unwrap(), "5");
+        assert!(!Path::new(&target).exists());
+
+        let first = run_with_cache(&script(""), Cache::default(), &cache_path);
+        assert_eq!(first.variables.get("copied-count").unwrap(), "2");
+        assert_eq!(first.variables.get("skipped-count").unwrap(), "0");
+
+        std::fs::write(format!("{}/b.txt", source), "changed").unwrap();
+        let second = run_with_cache(&script(""), first.cache.clone(), &cache_path);
+        assert_eq!(second.variables.get("copied-count").unwrap(), "1");
+        assert_eq!(second.variables.get("skipped-count").unwrap(), "1");
+        assert_eq!(second.variables.get("failed-count").unwrap(), "0");
+    }
+
+    #[test]
+    pub fn sync_removes_stale_files_and_directories() {
+        let dir = ScratchDir::new("sync");
+        let source = dir.join_string("source");
+        let target = dir.join_string("target");
+        let cache_path = dir.join_string("build.lb.cache");
+        std::fs::create_dir_all(format!("{}/keep", source)).unwrap();
+        std::fs::create_dir_all(format!("{}/stale-dir/sub", target)).unwrap();
+        std::fs::create_dir_all(format!("{}/.git", target)).unwrap();
+        std::fs::write(format!("{}/keep/a.txt", source), "a").unwrap();
+        std::fs::write(format!("{}/stale.txt", target), "old").unwrap();
+        std::fs::write(format!("{}/stale-dir/sub/b.txt", target), "old").unwrap();
+        let script = |options: &str| {
+            format!(
+                r#"
+        :sync "{}" "{}" --exclude .git --force {}
+        "#,
+                source, target, options
+            )
+        };
+
+        let dry_run = run_with_cache(&script("--dry-run"), Cache::default(), &cache_path);
+        assert_eq!(dry_run.variables.get("removed-count").unwrap(), "2");
+        assert!(Path::new(&format!("{}/stale.txt", target)).exists());
+
+        let synced = run_with_cache(&script(""), Cache::default(), &cache_path);
+        assert_eq!(synced.variables.get("copied-count").unwrap(), "1");
+        assert_eq!(synced.variables.get("removed-count").unwrap(), "2");
+        assert!(Path::new(&format!("{}/keep/a.txt", target)).exists());
+        assert!(!Path::new(&format!("{}/stale.txt", target)).exists());
+        assert!(!Path::new(&format!("{}/stale-dir", target)).exists());
+        assert!(Path::new(&format!("{}/.git", target)).exists());
+    }
+
     #[test]
     pub fn get_execute_strings() {
         let strings = str::get_line_strings("/c echo \"hello \\\"world\"".into());
         assert_eq!(
             strings,
-            vec!["/c", "echo", "hello \"world"]
+            ["/c", "echo", "hello \"world"]
                 .iter()
                 .map(|s| s.to_string())
                 .collect::<Vec<String>>()
         );
     }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn cached_command_is_restored_instead_of_run() {
+        let dir = ScratchDir::new("cached-e");
+        let input = dir.join_string("in.txt");
+        let output = dir.join_string("out.txt");
+        let runs = dir.join_string("runs.txt");
+        let cache_path = dir.join_string("build.lb.cache");
+        std::fs::write(&input, "content").unwrap();
+        let script = format!(
+            r#"
+        :cached-e --inputs {0} --outputs {1} -- sh -c "cp {0} {1}; echo run >> {2}"
+        "#,
+            input, output, runs
+        );
+        let run = |cache: Cache| {
+            let mut executor = Executor::with_cache(script.clone(), cache);
+            executor.cache_path = cache_path.clone();
+            executor.artifacts = ArtifactStore::new(&dir);
+            executor.execute();
+            executor
+        };
+
+        let first = run(Cache::default());
+        assert_eq!(first.cache.artifacts.len(), 1);
+        std::fs::remove_file(&output).unwrap();
+
+        let second = run(first.cache.clone());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "content");
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\n");
+
+        std::fs::write(&input, "changed").unwrap();
+        run(second.cache.clone());
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "changed");
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\nrun\n");
+    }
 }
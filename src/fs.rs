@@ -15,16 +15,18 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use crate::crc32::Crc32Table;
+use crate::cache::Cache;
+use crate::hash::HashAlgorithm;
 use crate::str;
 use crate::tuple;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashSet, VecDeque};
 use std::fs;
 use std::fs::{DirEntry, ReadDir};
 use std::path::{Component, Path, PathBuf};
 
 tuple!(CanonicalFsOp(source: PathBuf, target: PathBuf));
 tuple!(FsOp(source: String, target: String));
+tuple!(FileStat(modified: u64, size: u64));
 
 impl FsOp {
     pub fn canonicalize(&self) -> CanonicalFsOp {
@@ -51,7 +53,7 @@ fn canonicalize_any(path: &Path) -> PathBuf {
         }
     }
 
-    while let Some(c) = components.next() {
+    for c in components {
         match c {
             Component::RootDir => unreachable!(),
             Component::CurDir => {}
@@ -66,7 +68,12 @@ fn canonicalize_any(path: &Path) -> PathBuf {
 }
 
 pub fn get_source_and_target(input: String, op: &str) -> FsOp {
-    let parts = str::get_strings(input);
+    get_source_target_and_options(input, op).0
+}
+
+/// Source and target followed by any options, e.g. `"src" "dist" --exclude target`
+pub fn get_source_target_and_options(input: String, op: &str) -> (FsOp, Vec<String>) {
+    let parts = str::get_path_strings(input);
     let mut it = parts.into_iter();
     let source = it
         .next()
@@ -74,136 +81,712 @@ pub fn get_source_and_target(input: String, op: &str) -> FsOp {
     let target = it
         .next()
         .unwrap_or_else(|| panic!("missing target argument in {}", op));
-    (source, target).into()
+    ((source, target).into(), it.collect())
 }
 
-pub fn move_it(fs_op: &FsOp) {
-    fs::rename(&fs_op.source, &fs_op.target).unwrap_or_else(|err| {
-        panic!(
-            "failed to move from '{}' to '{}':\n{}",
-            fs_op.source, fs_op.target, err
-        )
-    });
+/// Options for copies, given after the source and target
+#[derive(Default, Clone)]
+pub struct CopyOptions {
+    pub filter: PathFilter,
+    pub symlinks: SymlinkPolicy,
+    /// Keep the modification time and permissions of the copied files
+    pub preserve: bool,
+    /// Only print what would be copied
+    pub dry_run: bool,
+    /// Let `:sync` remove files in the directory lb runs in or outside of it
+    pub force: bool,
 }
 
-pub fn copy(fs_op: &FsOp) {
-    fs::copy(&fs_op.source, &fs_op.target).unwrap_or_else(|err| {
-        panic!(
-            "failed to copy file from '{}' to '{}':\n{}",
-            fs_op.source, fs_op.target, err
+/// What a copy command did, set as variables after each copy
+#[derive(Debug, Default, Clone)]
+pub struct CopyStats {
+    pub copied: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: u64,
+    /// Stale files and directories removed by `:sync`
+    pub removed: usize,
+}
+
+impl CopyStats {
+    pub fn summary(&self) -> String {
+        let removed = if self.removed > 0 {
+            format!(", {} removed", self.removed)
+        } else {
+            String::new()
+        };
+        format!(
+            "{} copied ({} bytes), {} unchanged, {} failed{}",
+            self.copied, self.bytes, self.skipped, self.failed, removed
         )
-    });
+    }
+}
+
+/// What to do with symbolic links found while walking a directory
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Walk linked directories and copy the files links point to,
+    /// directories that have already been walked are skipped to avoid loops
+    #[default]
+    Follow,
+    /// Recreate the links in the target without following them
+    Link,
+    /// Leave links out
+    Skip,
+}
+
+impl CopyOptions {
+    pub fn parse(options: Vec<String>, op: &str) -> CopyOptions {
+        let mut copy_options = CopyOptions::default();
+        let mut it = options.into_iter();
+        while let Some(option) = it.next() {
+            let mut value = || {
+                it.next()
+                    .unwrap_or_else(|| panic!("'{}' requires a value in {}", option, op))
+            };
+            match option.as_str() {
+                "--preserve" => copy_options.preserve = true,
+                "--dry-run" => copy_options.dry_run = true,
+                "--force" => copy_options.force = true,
+                "--include" => copy_options.filter.include.push(IgnoreRule::new(&value())),
+                "--exclude" => copy_options.filter.exclude.push(IgnoreRule::new(&value())),
+                "--ignore-file" => copy_options.filter.add_ignore_file(Path::new(&value())),
+                "--symlinks" => {
+                    copy_options.symlinks = match value().as_str() {
+                        "follow" => SymlinkPolicy::Follow,
+                        "link" => SymlinkPolicy::Link,
+                        "skip" => SymlinkPolicy::Skip,
+                        v => panic!(
+                            "Unknown --symlinks value '{}' in {} (follow, link or skip)",
+                            v, op
+                        ),
+                    }
+                }
+                o => panic!("Unknown option '{}' in {}", o, op),
+            }
+        }
+        copy_options
+    }
+}
+
+/// A pattern from `--include`, `--exclude` or a `.gitignore`-style file.
+///
+/// Patterns containing a `/` (except at the end) match the path relative to the copied
+/// directory, other patterns match the name of a file or directory at any depth
+#[derive(Clone)]
+pub struct IgnoreRule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl IgnoreRule {
+    pub fn new(pattern: &str) -> IgnoreRule {
+        let negated = pattern.starts_with('!');
+        let pattern = pattern.trim_start_matches('!');
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        IgnoreRule {
+            pattern: pattern.trim_start_matches('/').to_string(),
+            negated,
+            dir_only,
+            anchored,
+        }
+    }
+
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_match(&self.pattern, relative)
+        } else {
+            let name = relative.rsplit(['/', '\\']).next().unwrap_or(relative);
+            glob_match(&self.pattern, name)
+        }
+    }
+}
+
+/// Decides which files and directories are walked while copying a directory
+#[derive(Default, Clone)]
+pub struct PathFilter {
+    pub include: Vec<IgnoreRule>,
+    pub exclude: Vec<IgnoreRule>,
+}
+
+impl PathFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Adds the lines of a `.gitignore`-style file as exclude patterns
+    pub fn add_ignore_file(&mut self, path: &Path) {
+        let content = fs::read_to_string(path)
+            .unwrap_or_else(|err| panic!("Failed to read ignore file '{:?}':\n{}", path, err));
+        self.exclude.extend(
+            content
+                .lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(IgnoreRule::new),
+        );
+    }
+
+    /// `relative` is the path relative to the directory being copied, excluded directories
+    /// are not descended into and `--include` only applies to files
+    pub fn allows(&self, relative: &str, is_dir: bool) -> bool {
+        let excluded = self
+            .exclude
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(relative, is_dir))
+            .is_some_and(|rule| !rule.negated);
+        if excluded {
+            return false;
+        }
+        is_dir
+            || self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|rule| rule.matches(relative, is_dir))
+    }
+}
+
+/// A path (quoted if it contains spaces) followed by the rest of the line as is
+pub fn get_path_and_content(input: String, op: &str) -> (String, String) {
+    let (path, rest) = if let Some(quoted) = input.strip_prefix('"') {
+        let end = quoted
+            .find('"')
+            .unwrap_or_else(|| panic!("missing closing quote in path in {}", op));
+        (quoted[..end].to_string(), &quoted[end + 1..])
+    } else {
+        let end = input.find([' ', '\t']).unwrap_or(input.len());
+        (input[..end].to_string(), &input[end..])
+    };
+    if path.is_empty() {
+        panic!("missing path argument in {}", op);
+    }
+    let content = rest.strip_prefix([' ', '\t']).unwrap_or(rest).to_string();
+    (path, content)
+}
+
+/// Turns an operation with a glob pattern as source into one operation per matching file
+/// (or directory if `dirs` is true), the target is then the directory to put them in
+/// and it's created unless it's a dry run
+pub fn expand_source(fs_op: FsOp, dirs: bool, dry_run: bool, op: &str) -> Vec<FsOp> {
+    if !is_glob(&fs_op.source) {
+        return vec![fs_op];
+    }
+    let ops = glob(&fs_op.source)
+        .into_iter()
+        .filter(|path| if dirs { path.is_dir() } else { path.is_file() })
+        .map(|path| {
+            let mut target = PathBuf::from(&fs_op.target);
+            target
+                .push(path.file_name().unwrap_or_else(|| {
+                    panic!("Failed to get file name of '{:?}' in {}", path, op)
+                }));
+            FsOp(
+                path.to_string_lossy().to_string(),
+                target.to_string_lossy().to_string(),
+            )
+        })
+        .collect::<Vec<FsOp>>();
+    if ops.is_empty() {
+        println!(
+            "Warning: '{}' did not match anything to {}",
+            fs_op.source, op
+        );
+    } else if !dry_run {
+        create_dirs(&PathBuf::from(&fs_op.target));
+    }
+    ops
+}
+
+pub fn move_it(fs_op: &FsOp) {
+    fs::rename(&fs_op.source, &fs_op.target)
+        .or_else(|err| {
+            if !is_cross_device(&err) {
+                return Err(err);
+            }
+            copy_preserving(Path::new(&fs_op.source), Path::new(&fs_op.target))?;
+            fs::remove_file(&fs_op.source)
+        })
+        .unwrap_or_else(|err| {
+            panic!(
+                "failed to move from '{}' to '{}':\n{}",
+                fs_op.source, fs_op.target, err
+            )
+        });
+}
+
+pub fn move_dir(fs_op: &FsOp) {
+    fs::rename(&fs_op.source, &fs_op.target)
+        .or_else(|err| {
+            if !is_cross_device(&err) {
+                return Err(err);
+            }
+            move_dir_by_copy(fs_op)
+        })
+        .unwrap_or_else(|err| {
+            panic!(
+                "failed to move directory from '{}' to '{}':\n{}",
+                fs_op.source, fs_op.target, err
+            )
+        });
+}
+
+/// Modification time (seconds since the unix epoch) and size of a file or directory
+pub fn stat(path: &str) -> Option<FileStat> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    Some(FileStat(modified, metadata.len()))
+}
+
+/// True if `path` has been modified after `than`, or if `than` does not exist
+pub fn is_newer(path: &str, than: &str) -> bool {
+    let modified = |p: &str| fs::metadata(p).and_then(|m| m.modified()).ok();
+    match (modified(path), modified(than)) {
+        (Some(path), Some(than)) => path > than,
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
+
+/// True if the path exists, or if anything matches it when it is a pattern
+pub fn exists(path: &str) -> bool {
+    if is_glob(path) {
+        !glob(path).is_empty()
+    } else {
+        Path::new(path).exists()
+    }
+}
+
+/// Removes a file or the files matching a pattern, files that don't exist are ignored,
+/// directories are left to `:rmd`
+pub fn remove_files(path: &str) {
+    let paths = if is_glob(path) {
+        glob(path)
+    } else {
+        let file = PathBuf::from(path);
+        if file.is_dir() && !file.is_symlink() {
+            panic!("'{}' is a directory, use :rmd to remove directories", path);
+        }
+        vec![file]
+    };
+    for path in paths {
+        if path.is_dir() && !path.is_symlink() {
+            println!(
+                "Warning: skipping {:?}, it is a directory, use :rmd to remove directories",
+                path
+            );
+        } else if path.is_file() || path.is_symlink() {
+            fs::remove_file(&path)
+                .unwrap_or_else(|err| panic!("failed to remove file '{:?}':\n{}", path, err));
+        }
+    }
 }
 
-pub fn copy_canonical(fs_op: &CanonicalFsOp) {
-    fs::copy(&fs_op.source, &fs_op.target).unwrap_or_else(|err| {
+/// Removes a directory and everything in it, refuses to remove anything that isn't inside
+/// `root` unless `force` is true
+pub fn remove_dir(path: &str, root: &Path, force: bool) {
+    let dir = PathBuf::from(path);
+    if !dir.exists() {
+        return;
+    }
+    if !dir.is_dir() {
+        panic!("'{}' is not a directory, use :rm to remove files", path);
+    }
+    if !force {
+        ensure_inside(path, root, "remove");
+    }
+    fs::remove_dir_all(&dir)
+        .unwrap_or_else(|err| panic!("failed to remove directory '{}':\n{}", path, err));
+}
+
+/// Panics unless the existing directory `path` is inside `root` (and not `root` itself),
+/// `action` is what would be done to it without `--force`
+pub fn ensure_inside(path: &str, root: &Path, action: &str) {
+    let dir = fs::canonicalize(path)
+        .unwrap_or_else(|err| panic!("failed to get full path of '{}':\n{}", path, err));
+    check_inside(&dir, path, root, action);
+}
+
+/// Like [ensure_inside] for a copy target, which doesn't have to exist yet. The path is
+/// resolved the same way copies resolve it, see [FsOp::canonicalize]
+pub fn ensure_target_inside(path: &str, root: &Path, action: &str) {
+    let target = canonicalize_any(Path::new(path));
+    let target = if target.is_absolute() {
+        target
+    } else {
+        let current_dir = std::env::current_dir()
+            .unwrap_or_else(|err| panic!("failed to get current directory:\n{}", err));
+        canonicalize_any(&current_dir.join(target))
+    };
+    check_inside(&target, path, root, action);
+}
+
+fn check_inside(dir: &Path, path: &str, root: &Path, action: &str) {
+    let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    if dir == root || !dir.starts_with(&root) {
         panic!(
-            "failed to copy file from '{:?}' to '{:?}':\n{}",
-            fs_op.source, fs_op.target, err
-        )
+            "refusing to {} '{}' since it is not a directory inside {:?}, use --force to {} it anyway",
+            action, path, root, action
+        );
+    }
+}
+
+pub fn make_dir(path: &str) {
+    fs::create_dir_all(path)
+        .unwrap_or_else(|err| panic!("failed to create directory '{}':\n{}", path, err));
+}
+
+/// Creates the file if it doesn't exist, otherwise updates its modification time
+pub fn touch(path: &str) {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|file| file.set_modified(std::time::SystemTime::now()))
+        .unwrap_or_else(|err| panic!("failed to touch '{}':\n{}", path, err));
+}
+
+pub fn write_file(path: &str, content: &str, append: bool) {
+    use std::io::Write;
+    fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .unwrap_or_else(|err| panic!("failed to write to '{}':\n{}", path, err));
+}
+
+/// `rename` can't move across file systems (e.g. from a tmpfs), so copy and delete instead
+fn move_dir_by_copy(fs_op: &FsOp) -> std::io::Result<()> {
+    let options = CopyOptions {
+        symlinks: SymlinkPolicy::Link,
+        ..Default::default()
+    };
+    // everything is moved, so `.lbignore` in the source does not apply
+    let paths = dir_copy_ops(fs_op, PathFilter::default(), &options);
+    let canonical_fs_op = fs_op.canonicalize();
+    create_dirs(&canonical_fs_op.target);
+    let mut result = Ok(());
+    copy_dir_inner(paths.clone(), &options, &mut |op| {
+        if result.is_ok() {
+            result = copy_preserving(&op.source, &op.target).map(|_| ());
+        }
     });
+    result?;
+    if let Some(op) = paths
+        .iter()
+        .find(|op| fs::symlink_metadata(&op.target).is_err())
+    {
+        return Err(std::io::Error::other(format!(
+            "{:?} was not copied, leaving the source as is",
+            op.source
+        )));
+    }
+
+    // deepest directories first, so setting a parent's modification time sticks
+    for op in paths.iter().rev().chain(Some(&canonical_fs_op)) {
+        if op.source.is_dir() && !op.source.is_symlink() {
+            preserve_metadata(&op.source, &op.target)?;
+        }
+    }
+    fs::remove_dir_all(&fs_op.source)
+}
+
+#[cfg(unix)]
+fn is_cross_device(err: &std::io::Error) -> bool {
+    // EXDEV
+    err.raw_os_error() == Some(18)
+}
+
+#[cfg(windows)]
+fn is_cross_device(err: &std::io::Error) -> bool {
+    // ERROR_NOT_SAME_DEVICE
+    err.raw_os_error() == Some(17)
 }
 
-pub fn cached_copy(fs_op: FsOp, cache: &mut HashMap<String, u32>, crc_table: &Crc32Table) {
-    if cache.contains_key(&fs_op.target) {
-        // target has been cached from before, check if source has same hash
-        let crc = cache.get(&fs_op.target).unwrap();
-        let target_exists = PathBuf::from(&fs_op.target).exists();
-        if target_exists
-            && fs::read(&fs_op.source)
-                .map(|b| crc_table.compare(&b, *crc))
-                .unwrap_or(false)
-        {
-            // no change, skip copy
-            return;
+#[cfg(not(any(unix, windows)))]
+fn is_cross_device(_err: &std::io::Error) -> bool {
+    false
+}
+
+/// Copies a file along with its permissions and modification time, the permissions are
+/// set last since the source may be read-only
+pub fn copy_preserving(source: &Path, target: &Path) -> std::io::Result<u64> {
+    let metadata = fs::metadata(source)?;
+    let mut reader = fs::File::open(source)?;
+    let mut writer = fs::File::create(target)?;
+    let bytes = std::io::copy(&mut reader, &mut writer)?;
+    writer.set_modified(metadata.modified()?)?;
+    drop(writer);
+    fs::set_permissions(target, metadata.permissions())?;
+    Ok(bytes)
+}
+
+/// Sets the permissions and modification time of the directory `target` to those of
+/// `source`, the modification time is only set where the platform allows it
+fn preserve_metadata(source: &Path, target: &Path) -> std::io::Result<()> {
+    let metadata = fs::metadata(source)?;
+    if let Ok(dir) = fs::File::open(target) {
+        let _ = dir.set_modified(metadata.modified()?);
+    }
+    fs::set_permissions(target, metadata.permissions())
+}
+
+pub fn copy(fs_op: &FsOp, options: &CopyOptions, stats: &mut CopyStats) {
+    let bytes = copy_file(Path::new(&fs_op.source), Path::new(&fs_op.target), options)
+        .unwrap_or_else(|err| {
+            panic!(
+                "failed to copy file from '{}' to '{}':\n{}",
+                fs_op.source, fs_op.target, err
+            )
+        });
+    stats.copied += 1;
+    stats.bytes += bytes;
+}
+
+/// Failing to copy a file in a directory is only a warning, it's counted in `stats`
+pub fn copy_canonical(fs_op: &CanonicalFsOp, options: &CopyOptions, stats: &mut CopyStats) {
+    match copy_file(&fs_op.source, &fs_op.target, options) {
+        Ok(bytes) => {
+            stats.copied += 1;
+            stats.bytes += bytes;
+        }
+        Err(err) => {
+            println!(
+                "Warning: failed to copy file from '{:?}' to '{:?}':\n{}",
+                fs_op.source, fs_op.target, err
+            );
+            stats.failed += 1;
         }
     }
+}
+
+/// Copies a file, or only prints what would be copied in a dry run
+fn copy_file(source: &Path, target: &Path, options: &CopyOptions) -> std::io::Result<u64> {
+    if options.dry_run {
+        println!("Would copy {:?} to {:?}", source, target);
+        fs::metadata(source).map(|m| m.len())
+    } else if options.preserve {
+        copy_preserving(source, target)
+    } else {
+        fs::copy(source, target)
+    }
+}
 
-    copy(&fs_op);
+/// Copies a file unless the cache knows the target already has the content of the source
+pub fn cached_copy(fs_op: FsOp, options: &CopyOptions, cache: &mut Cache, stats: &mut CopyStats) {
+    let canonical_fs_op = fs_op.canonicalize();
+    if cache.is_copy_unchanged(&canonical_fs_op.source, &canonical_fs_op.target) {
+        stats.skipped += 1;
+        return;
+    }
 
-    // update/store crc32 of target
-    let target_crc = fs::read(&fs_op.target).map(|b| crc_table.calculate(&b));
-    if let Ok(crc) = target_crc {
-        cache
-            .entry(fs_op.target)
-            .and_modify(|c| *c = crc)
-            .or_insert(crc);
+    copy(&fs_op, options, stats);
+    if !options.dry_run {
+        cache.insert_copy(&canonical_fs_op.source, &canonical_fs_op.target);
     }
 }
 
 pub fn cached_copy_canonical(
     fs_op: CanonicalFsOp,
-    cache: &mut HashMap<String, u32>,
-    crc_table: &Crc32Table,
+    options: &CopyOptions,
+    cache: &mut Cache,
+    stats: &mut CopyStats,
 ) {
-    if fs_op.target.exists() && cache.contains_key(&fs_op.target.to_string_lossy().to_string()) {
-        // target has been cached from before, check if source has same hash
-        let crc = cache
-            .get(&fs_op.target.to_string_lossy().to_string())
-            .unwrap();
-        if fs::read(&fs_op.source)
-            .map(|b| crc_table.compare(&b, *crc))
-            .unwrap_or(false)
-        {
-            // no change, skip copy
-            return;
-        }
+    if cache.is_copy_unchanged(&fs_op.source, &fs_op.target) {
+        stats.skipped += 1;
+        return;
     }
 
-    copy_canonical(&fs_op);
+    let failed = stats.failed;
+    copy_canonical(&fs_op, options, stats);
+    if !options.dry_run && stats.failed == failed {
+        cache.insert_copy(&fs_op.source, &fs_op.target);
+    }
+}
+
+/// Combines the contents of `inputs` (directories are walked recursively) and the paths
+/// of `outputs` into a single hash, used to check if a phase or group is up-to-date
+pub fn fingerprint(inputs: &[String], outputs: &[String], algorithm: HashAlgorithm) -> u128 {
+    let mut files = Vec::new();
+    for input in inputs {
+        let path = PathBuf::from(input);
+        if is_glob(input) {
+            files.extend(glob(input).into_iter().filter(|path| path.is_file()));
+        } else if path.is_dir() {
+            files.extend(
+                DirectoryTree::new(path)
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_file()),
+            );
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort();
 
-    // update/store crc32 of target
-    let target_crc = fs::read(&fs_op.target).map(|b| crc_table.calculate(&b));
-    if let Ok(crc) = target_crc {
-        cache
-            .entry(fs_op.target.to_string_lossy().to_string())
-            .and_modify(|c| *c = crc)
-            .or_insert(crc);
+    let mut hasher = algorithm.hasher();
+    for file in files {
+        hasher.update(file.to_string_lossy().as_bytes());
+        match algorithm.hash_file(&file) {
+            Ok(hash) => hasher.update(&hash.to_le_bytes()),
+            // a missing input still has to change the fingerprint once it shows up
+            Err(_) => hasher.update(b"<missing>"),
+        }
+    }
+    for output in outputs {
+        hasher.update(output.as_bytes());
+        hasher.update(&[0]);
     }
+
+    hasher.finish()
 }
 
-pub fn copy_dir(fs_op: &FsOp) {
-    let paths = create_recursive_dir_copy_ops(&fs_op);
-    copy_dir_inner(paths, &mut |op| copy_canonical(&op));
+pub fn copy_dir(fs_op: &FsOp, options: &CopyOptions, stats: &mut CopyStats) {
+    let paths = create_recursive_dir_copy_ops(fs_op, options);
+    copy_dir_inner(paths, options, &mut |op| {
+        copy_canonical(&op, options, stats)
+    });
+}
+
+pub fn cached_copy_dir(
+    fs_op: &FsOp,
+    options: &CopyOptions,
+    cache: &mut Cache,
+    stats: &mut CopyStats,
+) {
+    let paths = create_recursive_dir_copy_ops(fs_op, options);
+    copy_dir_inner(paths, options, &mut |op| {
+        cached_copy_canonical(op, options, cache, stats)
+    });
 }
 
-pub fn cached_copy_dir(fs_op: &FsOp, cache: &mut HashMap<String, u32>, crc_table: &Crc32Table) {
-    let paths = create_recursive_dir_copy_ops(&fs_op);
-    copy_dir_inner(paths, &mut |op| {
-        cached_copy_canonical(op, cache, &crc_table)
+/// Copies changed files like [cached_copy_dir], then removes files and directories in the
+/// target that are not in the source. Anything excluded by the filter is left alone
+pub fn sync_dir(fs_op: &FsOp, options: &CopyOptions, cache: &mut Cache, stats: &mut CopyStats) {
+    let paths = create_recursive_dir_copy_ops(fs_op, options);
+    let canonical_fs_op = fs_op.canonicalize();
+    let mut expected = HashSet::new();
+    for op in &paths {
+        // parents as well, directories are left out when using --include
+        let mut target = Some(op.target.as_path());
+        while let Some(path) = target.filter(|t| *t != canonical_fs_op.target) {
+            if !expected.insert(path.to_path_buf()) {
+                break;
+            }
+            target = path.parent();
+        }
+    }
+    copy_dir_inner(paths, options, &mut |op| {
+        cached_copy_canonical(op, options, cache, stats)
     });
+
+    if !canonical_fs_op.target.is_dir() {
+        return;
+    }
+    let filter = source_filter(&canonical_fs_op.source, options);
+    let mut removed_dirs: Vec<PathBuf> = Vec::new();
+    let tree = DirectoryTree::filtered(canonical_fs_op.target.clone(), filter, SymlinkPolicy::Link);
+    for entry in tree {
+        let path = entry
+            .unwrap_or_else(|err| panic!("File system error: {}", err))
+            .path();
+        let path = canonicalize_any(&path);
+        if expected.contains(&path) || removed_dirs.iter().any(|d| path.starts_with(d)) {
+            continue;
+        }
+
+        stats.removed += 1;
+        let is_dir = path.is_dir() && !path.is_symlink();
+        if options.dry_run {
+            println!("Would remove {:?}", path);
+        } else if is_dir {
+            fs::remove_dir_all(&path)
+                .unwrap_or_else(|err| panic!("failed to remove directory '{:?}':\n{}", path, err));
+        } else {
+            fs::remove_file(&path)
+                .unwrap_or_else(|err| panic!("failed to remove file '{:?}':\n{}", path, err));
+        }
+        if is_dir {
+            removed_dirs.push(path.clone());
+        }
+        cache.remove_target(&path);
+    }
 }
 
-fn copy_dir_inner<F>(paths: Vec<CanonicalFsOp>, copy_fn: &mut F)
+fn copy_dir_inner<F>(paths: Vec<CanonicalFsOp>, options: &CopyOptions, copy_fn: &mut F)
 where
-    F: FnMut(CanonicalFsOp) -> (),
+    F: FnMut(CanonicalFsOp),
 {
     let mut created_dirs: HashSet<PathBuf> = HashSet::new();
+    let mut create_dirs = |path_buf: &PathBuf| {
+        if !options.dry_run && created_dirs.insert(path_buf.clone()) {
+            create_dirs(path_buf);
+        }
+    };
     for op in paths {
-        if op.source.is_dir() {
-            if created_dirs.insert(op.target.clone()) {
-                create_dirs(&op.target);
+        if options.symlinks == SymlinkPolicy::Link && op.source.is_symlink() {
+            create_dirs(
+                &op.target
+                    .parent()
+                    .unwrap_or_else(|| panic!("Failed to get parent of '{:?}'", op.target))
+                    .to_path_buf(),
+            );
+            if options.dry_run {
+                println!(
+                    "Would link {:?} to {:?}",
+                    op.target,
+                    fs::read_link(&op.source)
+                );
+                continue;
             }
+            copy_symlink(&op.source, &op.target).unwrap_or_else(|err| {
+                panic!(
+                    "failed to copy link from '{:?}' to '{:?}':\n{}",
+                    op.source, op.target, err
+                )
+            });
+        } else if op.source.is_dir() {
+            create_dirs(&op.target);
         } else if op.source.is_file() {
-            let path_buf = op
-                .target
-                .parent()
-                .unwrap_or_else(|| panic!("Failed to get parent of '{:?}'", op.target))
-                .to_path_buf();
-            if created_dirs.insert(path_buf.clone()) {
-                create_dirs(&path_buf)
-            }
+            create_dirs(
+                &op.target
+                    .parent()
+                    .unwrap_or_else(|| panic!("Failed to get parent of '{:?}'", op.target))
+                    .to_path_buf(),
+            );
             // if we're lucky we've now got a directory to copy the file into
             copy_fn(op);
         } else {
-            println!("Warning: could not copy {:?} (file: {}, dir: {})", &op.source, &op.source.is_file(), &op.source.is_dir());
+            println!(
+                "Warning: could not copy {:?} (file: {}, dir: {})",
+                &op.source,
+                &op.source.is_file(),
+                &op.source.is_dir()
+            );
         }
     }
 }
 
 fn create_dirs(path_buf: &PathBuf) {
     if !path_buf.exists() {
-        fs::create_dir_all(&path_buf).unwrap_or_else(|err| {
+        fs::create_dir_all(path_buf).unwrap_or_else(|err| {
             panic!(
                 "Failed to create directories of path '{:?}':\n{}",
                 &path_buf, err
@@ -212,10 +795,55 @@ fn create_dirs(path_buf: &PathBuf) {
     }
 }
 
-fn create_recursive_dir_copy_ops(fs_op: &FsOp) -> Vec<CanonicalFsOp> {
+/// Creates a link at `target` pointing to where the link at `source` points
+pub fn copy_symlink(source: &Path, target: &Path) -> std::io::Result<()> {
+    let link = fs::read_link(source)?;
+    if target.is_symlink() || target.is_file() {
+        fs::remove_file(target)?;
+    }
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(link, target);
+    #[cfg(windows)]
+    return if source.is_dir() {
+        std::os::windows::fs::symlink_dir(link, target)
+    } else {
+        std::os::windows::fs::symlink_file(link, target)
+    };
+    #[cfg(not(any(unix, windows)))]
+    return Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        format!("links are not supported on this platform ({:?})", link),
+    ));
+}
+
+/// The filter of the options along with `.lbignore` in the source directory if it exists
+fn source_filter(source: &Path, options: &CopyOptions) -> PathFilter {
+    let mut filter = options.filter.clone();
+    let lbignore = source.join(".lbignore");
+    if lbignore.is_file() {
+        filter.add_ignore_file(&lbignore);
+    }
+    filter
+}
+
+fn create_recursive_dir_copy_ops(fs_op: &FsOp, options: &CopyOptions) -> Vec<CanonicalFsOp> {
+    let filter = source_filter(&fs_op.canonicalize().source, options);
+    dir_copy_ops(fs_op, filter, options)
+}
+
+fn dir_copy_ops(fs_op: &FsOp, filter: PathFilter, options: &CopyOptions) -> Vec<CanonicalFsOp> {
     let canonical_fs_op = fs_op.canonicalize();
-    let tree = DirectoryTree::new(canonical_fs_op.source.clone());
+    // directories without any included files should not be created
+    let only_files = !filter.include.is_empty();
+    let tree = DirectoryTree::filtered(canonical_fs_op.source.clone(), filter, options.symlinks);
     tree.into_iter()
+        .filter(|f| {
+            !only_files
+                || f.as_ref().map_or(true, |f| {
+                    let path = f.path();
+                    !path.is_dir() || (options.symlinks == SymlinkPolicy::Link && path.is_symlink())
+                })
+        })
         .map(|f| {
             let path = f
                 .unwrap_or_else(|err| panic!("File system error: {}", err))
@@ -242,23 +870,232 @@ fn create_recursive_dir_copy_ops(fs_op: &FsOp) -> Vec<CanonicalFsOp> {
         .collect::<Vec<CanonicalFsOp>>()
 }
 
-/// Does not take symlinks into account at this time.
+/// True if `path` is a pattern, a path that exists as written (like `pages/[id].js`) is
+/// used as is
+pub fn is_glob(path: &str) -> bool {
+    has_wildcards(path) && !Path::new(path).exists()
+}
+
+fn has_wildcards(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Finds all files and directories matching the pattern, sorted by path.
+///
+/// `*` matches anything but a path separator, `?` matches one character, `**` matches any
+/// number of directories and `[a-z]`/`[!a-z]` matches one character in/not in the class
+pub fn glob(pattern: &str) -> Vec<PathBuf> {
+    let segments = pattern.split(['/', '\\']).collect::<Vec<&str>>();
+    let literal_count = segments.iter().take_while(|s| !has_wildcards(s)).count();
+    if !is_glob(pattern) {
+        let path = PathBuf::from(pattern);
+        return if path.exists() {
+            vec![path]
+        } else {
+            Vec::new()
+        };
+    }
+
+    let base = PathBuf::from(segments[..literal_count].join("/"));
+    let mut matches = Vec::new();
+    glob_dir(
+        &base,
+        &segments[literal_count..],
+        &mut Vec::new(),
+        &mut matches,
+    );
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+/// Adds the paths in `dir` matching `segments`, only descending as far as the pattern
+/// goes into directories it can match, `ancestors` guards `**` against symlink loops
+fn glob_dir(
+    dir: &Path,
+    segments: &[&str],
+    ancestors: &mut Vec<FileId>,
+    matches: &mut Vec<PathBuf>,
+) {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let read_from = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let entries = match read_from.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    if *segment == "**" {
+        // `**` matching no directories at all
+        glob_dir(dir, rest, ancestors, matches);
+    }
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = dir.join(entry.file_name());
+        if *segment == "**" {
+            if rest.is_empty() {
+                matches.push(path.clone());
+            }
+            if !path.is_dir() {
+                continue;
+            }
+            match file_id(&path) {
+                Some(id) if ancestors.contains(&id) => continue,
+                Some(id) => {
+                    ancestors.push(id);
+                    glob_dir(&path, segments, ancestors, matches);
+                    ancestors.pop();
+                }
+                None => glob_dir(&path, segments, ancestors, matches),
+            }
+        } else {
+            let name = entry
+                .file_name()
+                .to_string_lossy()
+                .chars()
+                .collect::<Vec<char>>();
+            if !match_segment(&segment.chars().collect::<Vec<char>>(), &name) {
+                continue;
+            }
+            if rest.is_empty() {
+                matches.push(path);
+            } else if path.is_dir() {
+                glob_dir(&path, rest, ancestors, matches);
+            }
+        }
+    }
+}
+
+/// Matches a path (separated by `/` or `\`) against a glob pattern, see [glob]
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.split(['/', '\\']).collect::<Vec<&str>>();
+    let path = path.split(['/', '\\']).collect::<Vec<&str>>();
+    match_segments(&pattern, &path)
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| match_segments(rest, &path[i..])),
+        Some((segment, rest)) => match path.split_first() {
+            Some((name, path_rest)) => {
+                let segment = segment.chars().collect::<Vec<char>>();
+                let name = name.chars().collect::<Vec<char>>();
+                match_segment(&segment, &name) && match_segments(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn match_segment(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| match_segment(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && match_segment(&pattern[1..], &name[1..]),
+        Some('[') => match (match_class(&pattern[1..]), name.first()) {
+            (Some((matches, len)), Some(c)) => {
+                matches(*c) && match_segment(&pattern[len + 1..], &name[1..])
+            }
+            (Some(_), None) => false,
+            // no closing bracket, match it as a regular character
+            (None, _) => name.first() == Some(&'[') && match_segment(&pattern[1..], &name[1..]),
+        },
+        Some(c) => name.first() == Some(c) && match_segment(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Parses a character class (after the `[`), returns the matcher and how many
+/// characters the class used including the closing `]`
+fn match_class(class: &[char]) -> Option<(impl Fn(char) -> bool, usize)> {
+    let negated = matches!(class.first(), Some('!') | Some('^'));
+    let start = if negated { 1 } else { 0 };
+    // a `]` first in the class is a regular character
+    let end = class
+        .iter()
+        .skip(start + 1)
+        .position(|c| *c == ']')
+        .map(|p| p + start + 1)?;
+
+    let mut ranges = Vec::new();
+    let mut i = start;
+    while i < end {
+        if i + 2 < end && class[i + 1] == '-' {
+            ranges.push((class[i], class[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((class[i], class[i]));
+            i += 1;
+        }
+    }
+
+    let matcher =
+        move |c: char| ranges.iter().any(|(from, to)| (*from..=*to).contains(&c)) != negated;
+    Some((matcher, end + 1))
+}
+
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+/// Identifies a directory no matter which link it was reached through
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Identifies a directory no matter which link it was reached through
+#[cfg(not(unix))]
+fn file_id(path: &Path) -> Option<FileId> {
+    fs::canonicalize(path).ok()
+}
+
+/// Walks a directory breadth first, following symbolic links by default
+/// (see [SymlinkPolicy]) without walking the same directory twice.
 pub struct DirectoryTree {
     pub root: PathBuf,
     current_dir_iter: Option<ReadDir>,
     remaining_dirs: VecDeque<PathBuf>,
+    filter: PathFilter,
+    symlinks: SymlinkPolicy,
+    visited_dirs: HashSet<FileId>,
 }
 
 impl DirectoryTree {
     pub fn new(root: PathBuf) -> DirectoryTree {
+        DirectoryTree::filtered(root, PathFilter::default(), SymlinkPolicy::Follow)
+    }
+
+    /// Skips anything not allowed by the filter, without descending into skipped directories
+    pub fn filtered(root: PathBuf, filter: PathFilter, symlinks: SymlinkPolicy) -> DirectoryTree {
         let mut remaining = VecDeque::new();
         remaining.push_back(root.clone());
+        let visited_dirs = file_id(&root).into_iter().collect();
         DirectoryTree {
             root,
             current_dir_iter: None,
             remaining_dirs: remaining,
+            filter,
+            symlinks,
+            visited_dirs,
         }
     }
+
+    fn allows(&self, path: &Path, is_dir: bool) -> bool {
+        if self.filter.is_empty() {
+            return true;
+        }
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        self.filter
+            .allows(&relative.to_string_lossy().replace('\\', "/"), is_dir)
+    }
 }
 
 impl Iterator for DirectoryTree {
@@ -279,8 +1116,26 @@ impl Iterator for DirectoryTree {
             if let Some(ref mut curr_dir) = self.current_dir_iter {
                 if let Some(current) = curr_dir.next() {
                     if let Ok(ref it) = current {
-                        if it.path().is_dir() {
-                            self.remaining_dirs.push_back(it.path());
+                        let path = it.path();
+                        let is_link = it.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+                        if is_link && self.symlinks == SymlinkPolicy::Skip {
+                            continue;
+                        }
+                        let is_dir = path.is_dir();
+                        if !self.allows(&path, is_dir) {
+                            continue;
+                        }
+                        if is_dir && !(is_link && self.symlinks == SymlinkPolicy::Link) {
+                            match file_id(&path) {
+                                Some(id) if !self.visited_dirs.insert(id) => {
+                                    println!(
+                                        "Warning: skipping {:?}, the directory has already been walked (symlink loop?)",
+                                        path
+                                    );
+                                    continue;
+                                }
+                                _ => self.remaining_dirs.push_back(path),
+                            }
                         }
                     }
                     break current;
@@ -295,3 +1150,357 @@ impl Iterator for DirectoryTree {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{set_modified, ScratchDir};
+
+    #[test]
+    pub fn glob_star_does_not_cross_directories() {
+        assert!(glob_match("target/release/*.dll", "target/release/app.dll"));
+        assert!(!glob_match(
+            "target/release/*.dll",
+            "target/release/deps/app.dll"
+        ));
+        assert!(!glob_match("*.dll", "app.dll.pdb"));
+    }
+
+    #[test]
+    pub fn glob_double_star_matches_any_depth() {
+        assert!(glob_match("src/**/*.rs", "src/main.rs"));
+        assert!(glob_match("src/**/*.rs", "src/a/b/c.rs"));
+        assert!(glob_match("**", "src/a"));
+        assert!(!glob_match("src/**/*.rs", "tests/a.rs"));
+    }
+
+    #[test]
+    pub fn glob_question_mark_and_classes() {
+        assert!(glob_match("file?.txt", "file1.txt"));
+        assert!(!glob_match("file?.txt", "file10.txt"));
+        assert!(glob_match("[a-c]x[!0-9]", "bxy"));
+        assert!(!glob_match("[a-c]x[!0-9]", "bx1"));
+        assert!(!glob_match("[a-c]x", "dx"));
+        assert!(glob_match("[]]", "]"));
+        assert!(glob_match("[abc", "[abc"));
+    }
+
+    #[test]
+    pub fn glob_accepts_backslashes_as_separators() {
+        assert!(glob_match(r"target\release\*.exe", "target/release/lb.exe"));
+        assert!(glob_match("target/*/lb.exe", r"target\debug\lb.exe"));
+    }
+
+    #[test]
+    pub fn filter_excludes_names_at_any_depth() {
+        let mut filter = PathFilter::default();
+        filter.exclude.push(IgnoreRule::new("target/"));
+        filter.exclude.push(IgnoreRule::new("*.swp"));
+        assert!(!filter.allows("target", true));
+        assert!(!filter.allows("sub/target", true));
+        assert!(filter.allows("target", false));
+        assert!(!filter.allows("src/.main.rs.swp", false));
+        assert!(filter.allows("src/main.rs", false));
+    }
+
+    #[test]
+    pub fn filter_negation_and_anchored_patterns() {
+        let mut filter = PathFilter::default();
+        filter.exclude.push(IgnoreRule::new("*.log"));
+        filter.exclude.push(IgnoreRule::new("!keep.log"));
+        filter.exclude.push(IgnoreRule::new("/docs/internal"));
+        assert!(!filter.allows("a.log", false));
+        assert!(filter.allows("logs/keep.log", false));
+        assert!(!filter.allows("docs/internal", true));
+        assert!(filter.allows("src/docs/internal", true));
+    }
+
+    #[test]
+    pub fn filter_include_only_applies_to_files() {
+        let mut filter = PathFilter::default();
+        filter.include.push(IgnoreRule::new("*.dll"));
+        assert!(filter.allows("deps", true));
+        assert!(filter.allows("deps/a.dll", false));
+        assert!(!filter.allows("deps/a.pdb", false));
+    }
+
+    #[test]
+    pub fn copy_dir_skips_excluded_and_lbignored_paths() {
+        let dir = ScratchDir::new("copy-filter");
+        let source = dir.join("source");
+        fs::create_dir_all(source.join(".git")).unwrap();
+        fs::create_dir_all(source.join("src")).unwrap();
+        for file in [".git/HEAD", "src/main.rs", "src/.main.rs.swp", "notes.tmp"] {
+            fs::write(source.join(file), file).unwrap();
+        }
+        fs::write(source.join(".lbignore"), "# comment\n*.tmp\n").unwrap();
+
+        let target = dir.join("target");
+        let fs_op = FsOp(
+            source.to_string_lossy().to_string(),
+            target.to_string_lossy().to_string(),
+        );
+        let options = CopyOptions::parse(
+            vec![
+                "--exclude".into(),
+                ".git".into(),
+                "--exclude".into(),
+                "*.swp".into(),
+            ],
+            "test",
+        );
+        copy_dir(&fs_op, &options, &mut CopyStats::default());
+
+        assert!(target.join("src/main.rs").exists());
+        assert!(!target.join(".git").exists());
+        assert!(!target.join("src/.main.rs.swp").exists());
+        assert!(!target.join("notes.tmp").exists());
+    }
+
+    #[test]
+    pub fn move_dir_by_copy_keeps_modification_times() {
+        let dir = ScratchDir::new("move-dir");
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file.txt"), "content").unwrap();
+        let modified = set_modified(source.join("sub/file.txt"), 1_000_000);
+
+        let target = dir.join("target");
+        let fs_op = FsOp(
+            source.to_string_lossy().to_string(),
+            target.to_string_lossy().to_string(),
+        );
+        move_dir_by_copy(&fs_op).unwrap();
+
+        assert!(!source.exists());
+        let moved = fs::metadata(target.join("sub/file.txt")).unwrap();
+        assert_eq!(moved.modified().unwrap(), modified);
+        assert_eq!(
+            fs::read_to_string(target.join("sub/file.txt")).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    pub fn move_dir_by_copy_moves_ignored_and_read_only_files() {
+        let dir = ScratchDir::new("move-dir-all");
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("target")).unwrap();
+        fs::write(source.join(".lbignore"), "target/\n*.log\n").unwrap();
+        fs::write(source.join("target/build.o"), "object").unwrap();
+        fs::write(source.join("run.log"), "log").unwrap();
+        fs::write(source.join("readonly.txt"), "content").unwrap();
+        let modified = set_modified(source.join("readonly.txt"), 1_000_000);
+        let mut permissions = fs::metadata(source.join("readonly.txt"))
+            .unwrap()
+            .permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(source.join("readonly.txt"), permissions).unwrap();
+
+        let target = dir.join("moved");
+        let fs_op = FsOp(
+            source.to_string_lossy().to_string(),
+            target.to_string_lossy().to_string(),
+        );
+        move_dir_by_copy(&fs_op).unwrap();
+
+        assert!(!source.exists());
+        assert!(target.join(".lbignore").is_file());
+        assert!(target.join("target/build.o").is_file());
+        assert!(target.join("run.log").is_file());
+        let moved = fs::metadata(target.join("readonly.txt")).unwrap();
+        assert!(moved.permissions().readonly());
+        assert_eq!(moved.modified().unwrap(), modified);
+    }
+
+    #[test]
+    pub fn copy_with_preserve_copies_read_only_files() {
+        let dir = ScratchDir::new("preserve");
+        let source = dir.join("readonly.txt");
+        fs::write(&source, "content").unwrap();
+        let modified = set_modified(&source, 1_000_000);
+        let mut permissions = fs::metadata(&source).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&source, permissions).unwrap();
+
+        let target = dir.join("copy.txt");
+        let options = CopyOptions {
+            preserve: true,
+            ..Default::default()
+        };
+        let fs_op = FsOp(
+            source.to_string_lossy().to_string(),
+            target.to_string_lossy().to_string(),
+        );
+        let mut stats = CopyStats::default();
+        copy(&fs_op, &options, &mut stats);
+
+        assert_eq!(stats.copied, 1);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "content");
+        let copied = fs::metadata(&target).unwrap();
+        assert!(copied.permissions().readonly());
+        assert_eq!(copied.modified().unwrap(), modified);
+    }
+
+    #[test]
+    pub fn path_and_content_keeps_content_as_is() {
+        let (path, content) = get_path_and_content(r#""my dir/v.txt" 1.0  "x""#.into(), "test");
+        assert_eq!(path, "my dir/v.txt");
+        assert_eq!(content, r#"1.0  "x""#);
+        let (path, content) = get_path_and_content("v.txt\tversion 2\n".into(), "test");
+        assert_eq!(path, "v.txt");
+        assert_eq!(content, "version 2\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "refusing to remove")]
+    pub fn remove_dir_outside_of_root_requires_force() {
+        let dir = ScratchDir::new("remove-outside");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+
+        remove_dir(&dir.to_string_lossy(), &root, false);
+    }
+
+    #[test]
+    #[should_panic(expected = "use :rmd to remove directories")]
+    pub fn remove_files_refuses_directories() {
+        let dir = ScratchDir::new("remove-files-dir");
+        remove_files(&dir.to_string_lossy());
+    }
+
+    #[test]
+    pub fn remove_files_skips_directories_matched_by_a_pattern() {
+        let dir = ScratchDir::new("remove-files-pattern");
+        fs::create_dir_all(dir.join("sub.pdb")).unwrap();
+        fs::write(dir.join("app.pdb"), "").unwrap();
+
+        let base = dir.to_string_lossy().replace('\\', "/");
+        remove_files(&format!("{}/*.pdb", base));
+        assert!(!dir.join("app.pdb").exists());
+        assert!(dir.join("sub.pdb").is_dir());
+    }
+
+    #[test]
+    pub fn remove_dir_inside_root() {
+        let root = ScratchDir::new("remove-inside");
+        fs::create_dir_all(root.join("dist/sub")).unwrap();
+        fs::write(root.join("dist/sub/file"), "").unwrap();
+
+        remove_dir(&root.join("dist").to_string_lossy(), &root, false);
+        assert!(!root.join("dist").exists());
+        assert!(root.exists());
+    }
+
+    #[test]
+    pub fn newer_compares_modification_times() {
+        let dir = ScratchDir::new("newer");
+        let old = dir.join("old").to_string_lossy().to_string();
+        let new = dir.join("new").to_string_lossy().to_string();
+        fs::write(&old, "").unwrap();
+        fs::write(&new, "").unwrap();
+        set_modified(&old, 1000);
+        set_modified(&new, 2000);
+
+        assert!(is_newer(&new, &old));
+        assert!(!is_newer(&old, &new));
+        assert!(is_newer(&old, &dir.join("missing").to_string_lossy()));
+        assert!(!is_newer(&dir.join("missing").to_string_lossy(), &old));
+        assert_eq!(stat(&old).map(|s| s.modified), Some(1000));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn copy_dir_handles_symlink_loops_and_policies() {
+        let dir = ScratchDir::new("symlinks");
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        std::os::unix::fs::symlink("..", source.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink("file.txt", source.join("link.txt")).unwrap();
+
+        let copy_with = |policy: &str| {
+            let target = dir.join(policy);
+            let fs_op = FsOp(
+                source.to_string_lossy().to_string(),
+                target.to_string_lossy().to_string(),
+            );
+            copy_dir(
+                &fs_op,
+                &CopyOptions::parse(vec!["--symlinks".into(), policy.into()], "test"),
+                &mut CopyStats::default(),
+            );
+            target
+        };
+
+        let followed = copy_with("follow");
+        assert!(!followed.join("link.txt").is_symlink());
+        assert_eq!(
+            fs::read_to_string(followed.join("link.txt")).unwrap(),
+            "content"
+        );
+        assert!(!followed.join("sub/loop").exists());
+
+        let linked = copy_with("link");
+        assert_eq!(
+            fs::read_link(linked.join("link.txt")).unwrap(),
+            Path::new("file.txt")
+        );
+        assert_eq!(
+            fs::read_link(linked.join("sub/loop")).unwrap(),
+            Path::new("..")
+        );
+
+        let skipped = copy_with("skip");
+        assert!(skipped.join("file.txt").exists());
+        assert!(!skipped.join("link.txt").exists());
+        assert!(!skipped.join("sub/loop").exists());
+    }
+
+    #[test]
+    pub fn existing_paths_with_brackets_are_not_patterns() {
+        let dir = ScratchDir::new("glob-literal");
+        fs::create_dir_all(dir.join("pages")).unwrap();
+        for file in ["pages/[id].js", "pages/i.js"] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        let literal = dir.join("pages/[id].js").to_string_lossy().to_string();
+        let pattern = dir.join("pages/[a-z].js").to_string_lossy().to_string();
+
+        assert!(!is_glob(&literal));
+        assert!(is_glob(&pattern));
+        assert_eq!(glob(&literal), [PathBuf::from(&literal)]);
+        assert_eq!(glob(&pattern), [dir.join("pages/i.js")]);
+        let target = dir.join("out/[id].js").to_string_lossy().to_string();
+        let ops = expand_source(FsOp(literal.clone(), target.clone()), false, true, "test");
+        assert_eq!(ops.len(), 1);
+        assert_eq!(
+            (ops[0].source.as_str(), ops[0].target.as_str()),
+            (literal.as_str(), target.as_str())
+        );
+    }
+
+    #[test]
+    pub fn glob_finds_files_in_directory() {
+        let dir = ScratchDir::new("glob");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for file in ["a.txt", "b.log", "sub/c.txt"] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        let base = dir.to_string_lossy().replace('\\', "/");
+
+        let found = glob(&format!("{}/**/*.txt", base));
+        assert_eq!(found, [dir.join("a.txt"), dir.join("sub").join("c.txt")]);
+        assert_eq!(glob(&format!("{}/*.txt", base)), [dir.join("a.txt")]);
+        assert_eq!(glob(&format!("{}/*/*.txt", base)), [dir.join("sub/c.txt")]);
+        assert_eq!(
+            glob(&format!("{}/**", base)),
+            [
+                dir.join("a.txt"),
+                dir.join("b.log"),
+                dir.join("sub"),
+                dir.join("sub/c.txt")
+            ]
+        );
+    }
+}
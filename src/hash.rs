@@ -0,0 +1,315 @@
+/*
+ * localhost-build is an experimental build scripting language
+ * Copyright (C) 2021  Carl Erik Patrik Iwarson
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::crc32::Crc32;
+use std::convert::TryInto;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+/// Hashes data that is fed to it in pieces, the result is the same however it's split up
+pub trait StreamHasher {
+    fn update(&mut self, bytes: &[u8]);
+    /// Narrower hashes are zero-extended
+    fn finish(&self) -> u128;
+}
+
+/// The hash used for the contents of the cache, chosen with `:cachehash`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    #[default]
+    Crc32,
+    Xxh64,
+    Fnv1a128,
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+impl HashAlgorithm {
+    pub fn parse(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "crc32" => Some(HashAlgorithm::Crc32),
+            "xxh64" => Some(HashAlgorithm::Xxh64),
+            "fnv128" => Some(HashAlgorithm::Fnv1a128),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Crc32 => "crc32",
+            HashAlgorithm::Xxh64 => "xxh64",
+            HashAlgorithm::Fnv1a128 => "fnv128",
+        }
+    }
+
+    pub fn hasher(&self) -> Box<dyn StreamHasher> {
+        match self {
+            HashAlgorithm::Crc32 => Box::<Crc32>::default(),
+            HashAlgorithm::Xxh64 => Box::new(Xxh64::with_seed(0)),
+            HashAlgorithm::Fnv1a128 => Box::<Fnv1a128>::default(),
+        }
+    }
+
+    pub fn hash(&self, bytes: &[u8]) -> u128 {
+        let mut hasher = self.hasher();
+        hasher.update(bytes);
+        hasher.finish()
+    }
+
+    /// Reads the file in chunks instead of loading all of it
+    pub fn hash_file(&self, path: &Path) -> std::io::Result<u128> {
+        let mut file = fs::File::open(path)?;
+        let mut hasher = self.hasher();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+            match file.read(&mut buffer) {
+                Ok(0) => return Ok(hasher.finish()),
+                Ok(read) => hasher.update(&buffer[..read]),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+const PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME64_3: u64 = 0x165667B19E3779F9;
+const PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME64_5: u64 = 0x27D4EB2F165667C5;
+
+/// xxHash64, see https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md
+pub struct Xxh64 {
+    seed: u64,
+    accumulators: [u64; 4],
+    buffer: [u8; 32],
+    buffered: usize,
+    total_len: u64,
+}
+
+impl Xxh64 {
+    pub fn with_seed(seed: u64) -> Xxh64 {
+        Xxh64 {
+            seed,
+            accumulators: [
+                seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+                seed.wrapping_add(PRIME64_2),
+                seed,
+                seed.wrapping_sub(PRIME64_1),
+            ],
+            buffer: [0; 32],
+            buffered: 0,
+            total_len: 0,
+        }
+    }
+
+    fn round(acc: u64, lane: u64) -> u64 {
+        acc.wrapping_add(lane.wrapping_mul(PRIME64_2))
+            .rotate_left(31)
+            .wrapping_mul(PRIME64_1)
+    }
+
+    fn merge(acc: u64, value: u64) -> u64 {
+        (acc ^ Self::round(0, value))
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4)
+    }
+
+    fn stripe(accumulators: &mut [u64; 4], stripe: &[u8]) {
+        for (acc, lane) in accumulators.iter_mut().zip(stripe.chunks_exact(8)) {
+            *acc = Self::round(*acc, read_u64(lane));
+        }
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+impl StreamHasher for Xxh64 {
+    fn update(&mut self, mut bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+
+        if self.buffered > 0 {
+            let take = usize::min(32 - self.buffered, bytes.len());
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&bytes[..take]);
+            self.buffered += take;
+            bytes = &bytes[take..];
+            if self.buffered < 32 {
+                return;
+            }
+            Self::stripe(&mut self.accumulators, &self.buffer);
+            self.buffered = 0;
+        }
+
+        let mut stripes = bytes.chunks_exact(32);
+        for stripe in &mut stripes {
+            Self::stripe(&mut self.accumulators, stripe);
+        }
+        let rest = stripes.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    fn finish(&self) -> u128 {
+        let [v1, v2, v3, v4] = self.accumulators;
+        let mut hash = if self.total_len >= 32 {
+            let hash = v1
+                .rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            [v1, v2, v3, v4]
+                .iter()
+                .fold(hash, |hash, v| Self::merge(hash, *v))
+        } else {
+            self.seed.wrapping_add(PRIME64_5)
+        };
+        hash = hash.wrapping_add(self.total_len);
+
+        let mut rest = &self.buffer[..self.buffered];
+        while rest.len() >= 8 {
+            hash ^= Self::round(0, read_u64(rest));
+            hash = hash
+                .rotate_left(27)
+                .wrapping_mul(PRIME64_1)
+                .wrapping_add(PRIME64_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            hash ^= (read_u32(rest) as u64).wrapping_mul(PRIME64_1);
+            hash = hash
+                .rotate_left(23)
+                .wrapping_mul(PRIME64_2)
+                .wrapping_add(PRIME64_3);
+            rest = &rest[4..];
+        }
+        for byte in rest {
+            hash ^= (*byte as u64).wrapping_mul(PRIME64_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64_3);
+        hash ^= hash >> 32;
+        hash as u128
+    }
+}
+
+/// 128-bit FNV-1a, slower than xxh64 but with a much lower chance of collisions
+pub struct Fnv1a128 {
+    hash: u128,
+}
+
+const FNV128_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV128_PRIME: u128 = 0x0000000001000000000000000000013B;
+
+impl Default for Fnv1a128 {
+    fn default() -> Self {
+        Fnv1a128 {
+            hash: FNV128_OFFSET,
+        }
+    }
+}
+
+impl StreamHasher for Fnv1a128 {
+    fn update(&mut self, bytes: &[u8]) {
+        self.hash = bytes.iter().fold(self.hash, |hash, byte| {
+            (hash ^ *byte as u128).wrapping_mul(FNV128_PRIME)
+        });
+    }
+
+    fn finish(&self) -> u128 {
+        self.hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    const ALGORITHMS: [HashAlgorithm; 3] = [
+        HashAlgorithm::Crc32,
+        HashAlgorithm::Xxh64,
+        HashAlgorithm::Fnv1a128,
+    ];
+
+    #[test]
+    pub fn xxh64_known_vectors() {
+        let xxh64 = HashAlgorithm::Xxh64;
+        assert_eq!(xxh64.hash(b""), 0xef46_db37_51d8_e999);
+        assert_eq!(xxh64.hash(&[42]), 0x0a9e_dece_beb0_3ae4);
+        assert_eq!(xxh64.hash(b"Hello, world!\0"), 0x7b06_c531_ea43_e89f);
+        let bytes = (0..100).collect::<Vec<u8>>();
+        assert_eq!(xxh64.hash(&bytes), 0x6ac1_e580_3216_6597);
+
+        let mut seeded = Xxh64::with_seed(0xae05_4331_1b70_2d91);
+        seeded.update(&bytes);
+        assert_eq!(seeded.finish(), 0x567e_355e_0682_e1f1);
+    }
+
+    #[test]
+    pub fn fnv128_known_vectors() {
+        let fnv128 = HashAlgorithm::Fnv1a128;
+        assert_eq!(fnv128.hash(b""), 0x6c62272e07bb014262b821756295c58d);
+        assert_eq!(fnv128.hash(b"a"), 0xd228cb696f1a8caf78912b704e4a8964);
+        assert_eq!(fnv128.hash(b"foobar"), 0x343e1662793c64bf6f0d3597ba446f18);
+    }
+
+    #[test]
+    pub fn crc32_known_vector() {
+        assert_eq!(HashAlgorithm::Crc32.hash(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    pub fn split_input_gives_the_same_hash() {
+        let bytes = (0..1000u32).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        for algorithm in ALGORITHMS {
+            let whole = algorithm.hash(&bytes);
+            for chunk_size in [1, 3, 8, 31, 32, 33, 100] {
+                let mut hasher = algorithm.hasher();
+                for chunk in bytes.chunks(chunk_size) {
+                    hasher.update(chunk);
+                }
+                assert_eq!(hasher.finish(), whole, "{:?} {}", algorithm, chunk_size);
+            }
+        }
+    }
+
+    #[test]
+    pub fn file_is_hashed_like_its_content() {
+        let dir = ScratchDir::new("hash-file");
+        let path = dir.join("file");
+        let bytes = (0..CHUNK_SIZE * 2 + 5)
+            .map(|i| i as u8)
+            .collect::<Vec<u8>>();
+        fs::write(&path, &bytes).unwrap();
+
+        for algorithm in ALGORITHMS {
+            assert_eq!(algorithm.hash_file(&path).unwrap(), algorithm.hash(&bytes));
+            assert_eq!(HashAlgorithm::parse(algorithm.name()), Some(algorithm));
+        }
+    }
+}
@@ -0,0 +1,40 @@
+/*
+ * localhost-build is an experimental build scripting language
+ * Copyright (C) 2021  Carl Erik Patrik Iwarson
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// the files in lblib/ compiled into lb, as (import path, content), generated by build.rs
+include!(concat!(env!("OUT_DIR"), "/lblib.rs"));
+
+/// Content of a bundled file, e.g. `lblib/rust.lb`
+pub fn get(name: &str) -> Option<&'static str> {
+    FILES
+        .iter()
+        .find(|(file, _)| *file == name)
+        .map(|(_, content)| *content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn standard_library_is_bundled() {
+        assert!(get("lblib/rust.lb").unwrap().contains("[build"));
+        assert!(get("lblib/util.lb").is_some());
+        assert!(get("rust.lb").is_none());
+    }
+}
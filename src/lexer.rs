@@ -17,6 +17,7 @@
  */
 use std::iter::FromIterator;
 
+use crate::preprocessor;
 use crate::token::*;
 use std::cmp::Ordering;
 
@@ -125,8 +126,13 @@ impl Lexer {
 
     fn get_group_definition(&mut self) -> Token {
         self.eat(); // [
-        let name = self.get_ident();
+        let mut name = self.get_group_name();
         self.eat_whitespace_except_newlines();
+        let overrides = name == preprocessor::OVERRIDE && Self::is_group_name_char(self.buffer[0]);
+        if overrides {
+            name = self.get_group_name();
+            self.eat_whitespace_except_newlines();
+        }
         let mut args = Vec::new();
         if self.buffer[0] != '\n' {
             while self.buffer[0] == '$' {
@@ -156,12 +162,13 @@ impl Lexer {
             name,
             args,
             commands,
+            overrides,
         }))
     }
 
     fn get_execute_group(&mut self) -> Token {
         self.eat();
-        let name = self.get_ident();
+        let name = self.get_group_name();
         self.eat_whitespace_except_newlines();
         let mut args = Vec::new();
         while self.buffer[0] != '\n' && !self.eof {
@@ -180,6 +187,20 @@ impl Lexer {
         Token::new(TokenKind::ExecuteGroup(name, args))
     }
 
+    fn is_group_name_char(c: char) -> bool {
+        matches!(c, 'A' ..= 'Z' | 'a' ..= 'z' | '0' ..= '9' | '-' | '_' | '.')
+    }
+
+    /// Like an identifier, but `.` separates the namespace of imported groups
+    fn get_group_name(&mut self) -> String {
+        let mut name = Vec::new();
+        while Self::is_group_name_char(self.buffer[0]) && !self.eof {
+            name.push(self.buffer[0]);
+            self.eat();
+        }
+        String::from_iter(name)
+    }
+
     fn get_ident(&mut self) -> String {
         let mut ident = Vec::new();
         while matches!(self.buffer[0], 'A' ..= 'Z' | 'a' ..= 'z' | '0' ..= '9' | '-' | '_')
@@ -15,27 +15,85 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+pub mod artifacts;
+pub mod cache;
+pub mod cargo;
 pub mod crc32;
 pub mod executor;
 pub mod fs;
+pub mod hash;
+pub mod json;
+pub mod lblib;
 pub mod lexer;
 pub mod macros;
 pub mod preprocessor;
+pub mod report;
 pub mod str;
 pub mod table;
+#[cfg(test)]
+mod testing;
 pub mod token;
 
+/// The values of an option that can be repeated, e.g. `--set a=1 --set b=2`
+fn option_values<'a>(args: &'a [String], option: &str) -> Vec<&'a String> {
+    let mut values = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == option {
+            values.push(
+                args.next()
+                    .unwrap_or_else(|| panic!("expected a value after {}", option)),
+            );
+        }
+    }
+    values
+}
+
+/// `--set name=value`
+fn cli_variables(args: &[String]) -> Vec<(String, String)> {
+    option_values(args, "--set")
+        .into_iter()
+        .map(|variable| {
+            let (name, value) = variable
+                .split_once('=')
+                .unwrap_or_else(|| panic!("expected name=value after --set, got '{}'", variable));
+            (name.trim_start_matches('$').into(), value.into())
+        })
+        .collect()
+}
+
 fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if args.iter().any(|arg| arg == "--list-lib") {
+        for (name, on_disk) in preprocessor::bundled_files() {
+            match on_disk {
+                Some(path) => println!("{} (overridden by {:?})", name, path),
+                None => println!("{}", name),
+            }
+        }
+        return;
+    }
+    if args.iter().any(|arg| arg == "--clear-cache") {
+        if cache::clear(executor::CACHE_FILE) {
+            println!("Removed {}", executor::CACHE_FILE);
+        }
+        let root = std::env::current_dir().expect("failed to get current directory");
+        artifacts::ArtifactStore::new(&root).clear();
+        return;
+    }
+
     let script =
         std::fs::read_to_string("build.lb").unwrap_or_else(|_| panic!("couldn't read build.lb"));
-    let cache = std::fs::read_to_string("build.lb.cache");
-
-    let mut executor = if let Ok(cache) = cache {
-        let cache = str::parse_cache(cache);
-        executor::Executor::with_cache(script, cache)
-    } else {
-        executor::Executor::new(script)
-    };
+    let cache = cache::Cache::load(executor::CACHE_FILE);
+    let mut executor = executor::Executor::with_cache(script, cache);
+    executor.set_cli_variables(cli_variables(&args));
+    executor.set_reports(
+        option_values(&args, "--report")
+            .into_iter()
+            .map(|format| report::ReportFormat::parse(format))
+            .collect(),
+    );
+    executor.set_timings(args.iter().any(|arg| arg == "--timings"));
 
     executor.execute();
 }
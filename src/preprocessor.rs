@@ -15,12 +15,15 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use crate::executor::EACH;
+use crate::lblib;
 use crate::lexer::Lexer;
 use crate::token::{GroupDefinition, TokenKind};
 use crate::tuple;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 
 tuple!(PreprocessedScript(script: String));
 
@@ -35,6 +38,18 @@ pub fn run(mut lexer: Lexer) -> HashMap<String, GroupDefinition> {
     while token.kind != TokenKind::EndOfText {
         //println!("preprocessoring token {:?}", &token.kind);
         if let TokenKind::GroupDefinition(group_def) = token.kind {
+            let replacing = groups.contains_key(&group_def.name);
+            if replacing && !group_def.overrides {
+                println!(
+                    "Warning: group '{0}' is defined more than once, the last definition is used. Use [{1} {0} ...] if this is intended",
+                    group_def.name, OVERRIDE
+                );
+            } else if !replacing && group_def.overrides {
+                println!(
+                    "Warning: [{} {}] does not override any group",
+                    OVERRIDE, group_def.name
+                );
+            }
             groups.insert(group_def.name.clone(), group_def);
         }
         token = lexer.next_token();
@@ -43,68 +58,476 @@ pub fn run(mut lexer: Lexer) -> HashMap<String, GroupDefinition> {
     groups
 }
 
-fn perform_imports_inner(script: String) -> String {
-    let script_lines = script
+const IMPORT: &str = "&import(";
+const OPTIONAL_IMPORT: &str = "&import?(";
+const IF_OS: &str = "&if-os(";
+const IF_ENV: &str = "&if-env(";
+const ELSE: &str = "&else";
+const END_IF: &str = "&endif";
+const INIT: &str = "&init";
+const END_INIT: &str = "&endinit";
+/// `[override name ...]` replaces an earlier group on purpose, without a warning
+pub const OVERRIDE: &str = "override";
+
+/// The path in an `&import(path)` line, and if it was an `&import?(path)` that may be missing
+fn import_path(line: &str) -> Option<(&str, bool)> {
+    let (rest, optional) = match line.strip_prefix(IMPORT) {
+        Some(rest) => (rest, false),
+        None => (line.strip_prefix(OPTIONAL_IMPORT)?, true),
+    };
+    Some((rest.strip_suffix(')')?, optional))
+}
+
+enum Directive {
+    If(bool),
+    Else,
+    EndIf,
+    Init,
+    EndInit,
+}
+
+/// `&if-os(linux)`, `&if-env(NAME)` and `&if-env(NAME=value)`, ended by `&endif` with an
+/// optional `&else`. Lines in the branch not taken are removed before anything else
+fn directive(line: &str) -> Option<Directive> {
+    let line = line.trim();
+    if line == ELSE {
+        return Some(Directive::Else);
+    }
+    if line == END_IF {
+        return Some(Directive::EndIf);
+    }
+    if line == INIT {
+        return Some(Directive::Init);
+    }
+    if line == END_INIT {
+        return Some(Directive::EndInit);
+    }
+    let argument = |prefix: &str| {
+        line.strip_prefix(prefix).map(|rest| {
+            rest.strip_suffix(')')
+                .unwrap_or_else(|| panic!("Missing ')' in '{}'", line))
+                .trim()
+        })
+    };
+    if let Some(os) = argument(IF_OS) {
+        return Some(Directive::If(
+            os == std::env::consts::OS || os == std::env::consts::FAMILY,
+        ));
+    }
+    if let Some(env) = argument(IF_ENV) {
+        let matches = match env.split_once('=') {
+            Some((name, value)) => std::env::var(name.trim()).is_ok_and(|v| v == value.trim()),
+            None => std::env::var_os(env).is_some_and(|v| !v.is_empty()),
+        };
+        return Some(Directive::If(matches));
+    }
+    if line.starts_with("&if") {
+        panic!(
+            "Unknown condition '{}', expected {}...) or {}...)",
+            line, IF_OS, IF_ENV
+        );
+    }
+    None
+}
+
+/// Where imports are looked for, in order: next to the importing file, the directories in
+/// `LB_PATH`, `~/.config/lb/lib` and next to the lb executable. `lblib/` files that aren't
+/// found in any of them are taken from the ones bundled with lb
+fn search_dirs(importing_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![importing_dir.to_path_buf()];
+    if let Some(lb_path) = std::env::var_os("LB_PATH") {
+        dirs.extend(std::env::split_paths(&lb_path).filter(|p| !p.as_os_str().is_empty()));
+    }
+    if let Some(home) = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+        dirs.push(PathBuf::from(home).join(".config").join("lb").join("lib"));
+    }
+    if let Some(exe_dir) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    {
+        dirs.push(exe_dir);
+    }
+    dirs
+}
+
+/// Splits a line defining (`[name`) or calling (`!name`) a group into what comes before the
+/// name and the name with the rest of the line
+fn split_at_group(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    let rest = if let Some(definition) = trimmed.strip_prefix('[') {
+        match definition.strip_prefix(OVERRIDE) {
+            Some(rest) if rest.starts_with([' ', '\t']) => rest.trim_start(),
+            _ => definition,
+        }
+    } else if let Some(each) = trimmed.strip_prefix(EACH) {
+        // the group run by `:each`
+        if !each.starts_with([' ', '\t']) {
+            return None;
+        }
+        each.trim_start()
+    } else {
+        trimmed.strip_prefix('!')?
+    };
+    Some(line.split_at(line.len() - rest.len()))
+}
+
+fn group_name(rest: &str) -> &str {
+    rest.split([' ', '\t']).next().unwrap_or("")
+}
+
+/// Prefixes the groups defined in `script` and the calls to them (`!name` and `:each name`)
+/// with `namespace.`, groups from files it imports are left alone. Variables and phases are
+/// shared on purpose, so the importing file can change the module's defaults
+fn add_namespace(script: &str, namespace: &str) -> String {
+    if !namespace
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        panic!("Invalid namespace '{}' in import", namespace);
+    }
+    let groups = script
         .lines()
-        .map(|l| {
-            if !(l.starts_with("&import(") && l.ends_with(')')) {
-                l.to_string()
-            } else {
-                let l = l.get(
-                    "&import(".len()..l.rfind(')')
-                        .unwrap_or_else(|| {
-                            panic!(
-                                "Import line '{}' did not end with a closing parenthesis ')'",
-                                l
-                            )
-                        }),
-                )
-                    .unwrap_or_else(|| panic!("Malformed import line '{}'", l));
-                let dir_to_use = if l.starts_with("lblib/") {
-                    let mut executable_path = match std::env::current_exe() {
-                        Ok(path) => path,
-                        Err(e) => {
-                            panic!(
-                                "Cannot use lblib import, failed to get path to lb.exe:\n{}",
-                                e
-                            )
+        .filter(|line| line.trim_start().starts_with('['))
+        .filter_map(split_at_group)
+        .map(|(_, rest)| group_name(rest))
+        .collect::<HashSet<&str>>();
+
+    script
+        .lines()
+        .map(|line| match split_at_group(line) {
+            Some((start, rest)) if groups.contains(group_name(rest)) => {
+                format!("{}{}.{}", start, namespace, rest)
+            }
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Looks for the import in [search_dirs], returns the directories searched if it's not found
+fn find_on_disk(import: &str, importing_file: &Path) -> Result<PathBuf, Vec<PathBuf>> {
+    let path = Path::new(import);
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    let importing_dir = match importing_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dirs = search_dirs(importing_dir);
+    dirs.iter()
+        .map(|dir| dir.join(path))
+        .find(|path| path.is_file())
+        .ok_or(dirs)
+}
+
+/// Prefix of the paths given to bundled files in import cycle errors
+const BUNDLED: &str = "<bundled>";
+
+enum Source {
+    File(PathBuf),
+    Bundled(&'static str),
+}
+
+/// The files bundled with lb and the file on disk that is used instead, if any
+pub fn bundled_files() -> Vec<(&'static str, Option<PathBuf>)> {
+    lblib::FILES
+        .iter()
+        .map(|(name, _)| (*name, find_on_disk(name, Path::new("build.lb")).ok()))
+        .collect()
+}
+
+/// The lines of a script with its imports, `&init` sections are run before everything else
+#[derive(Default)]
+struct Expanded {
+    init: Vec<String>,
+    body: Vec<String>,
+}
+
+#[derive(Default)]
+struct Importer {
+    /// Canonical path and namespace of everything imported so far
+    imported: HashSet<(PathBuf, Option<String>)>,
+    /// The files currently being imported, used to show the chain when there is a cycle
+    chain: Vec<PathBuf>,
+}
+
+impl Importer {
+    /// The file to import, or the bundled one if there is none on disk. The path is used to
+    /// recognize the import, bundled files get one that can't exist. Returns the path to the
+    /// bundle or the directories searched if it can't be found
+    fn resolve(import: &str, importing_file: &Path) -> Result<(PathBuf, Source), Vec<PathBuf>> {
+        match find_on_disk(import, importing_file) {
+            Ok(path) => {
+                let canonical = std::fs::canonicalize(&path).unwrap_or_else(|err| {
+                    panic!("Failed to open {:?} for importing:\n{}", path, err)
+                });
+                Ok((canonical, Source::File(path)))
+            }
+            Err(dirs) => lblib::get(import)
+                .map(|content| (Path::new(BUNDLED).join(import), Source::Bundled(content)))
+                .ok_or(dirs),
+        }
+    }
+
+    fn expand(&mut self, script: &str, file: &Path) -> Expanded {
+        // (condition, in the &else branch) of every &if the line is in
+        let mut conditions: Vec<(bool, bool)> = Vec::new();
+        let mut in_init = false;
+        let mut init = Vec::new();
+        let mut imported_init = Vec::new();
+        let mut body = Vec::new();
+        for line in script.lines() {
+            match directive(line) {
+                Some(Directive::If(condition)) => conditions.push((condition, false)),
+                Some(Directive::Else) => match conditions.last_mut() {
+                    Some((condition, in_else @ false)) => {
+                        *condition = !*condition;
+                        *in_else = true;
+                    }
+                    _ => panic!("{} without a matching &if in {:?}", ELSE, file),
+                },
+                Some(Directive::EndIf) => {
+                    conditions.pop().unwrap_or_else(|| {
+                        panic!("{} without a matching &if in {:?}", END_IF, file)
+                    });
+                }
+                None if !conditions.iter().all(|(condition, _)| *condition) => {}
+                Some(Directive::Init) if !in_init => in_init = true,
+                Some(Directive::EndInit) if in_init => in_init = false,
+                Some(Directive::Init) | Some(Directive::EndInit) => {
+                    panic!("Unmatched {} or {} in {:?}", INIT, END_INIT, file)
+                }
+                None => match import_path(line) {
+                    None if in_init => init.push(line.to_string()),
+                    None => body.push(line.to_string()),
+                    Some(_) if in_init => panic!("Imports can't be in {} in {:?}", INIT, file),
+                    Some((import, optional)) => {
+                        let (import, namespace) = match import.rsplit_once(" as ") {
+                            Some((import, namespace)) => (import.trim(), Some(namespace.trim())),
+                            None => (import, None),
+                        };
+                        let expanded = self.import(import, namespace, optional, file);
+                        imported_init.extend(expanded.init);
+                        if expanded.body.is_empty() {
+                            // an empty line where the import was, as before
+                            body.push(String::new());
                         }
-                    };
-                    if !executable_path.pop() {
-                        panic!("Failed to get parent directory of directory containing lb.exe, cannot use lblib import");
+                        body.extend(expanded.body);
                     }
+                },
+            }
+        }
+        if !conditions.is_empty() {
+            panic!("Missing {} in {:?}", END_IF, file);
+        }
+        if in_init {
+            panic!("Missing {} in {:?}", END_INIT, file);
+        }
+        // the importer's own init comes first so it can set variables before modules use ?=
+        init.extend(imported_init);
+        Expanded { init, body }
+    }
+
+    fn import(
+        &mut self,
+        import: &str,
+        namespace: Option<&str>,
+        optional: bool,
+        importing_file: &Path,
+    ) -> Expanded {
+        let (canonical, source) = match Self::resolve(import, importing_file) {
+            Ok(resolved) => resolved,
+            Err(_) if optional => return Expanded::default(),
+            Err(dirs) => panic!(
+                "Failed to find '{}' imported by {:?}, looked in:\n{}",
+                import,
+                importing_file,
+                dirs.iter()
+                    .map(|d| format!("  {:?}", d))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+        };
+
+        if let Some(start) = self.chain.iter().position(|p| *p == canonical) {
+            let chain = self.chain[start..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.to_string_lossy().to_string())
+                .collect::<Vec<String>>()
+                .join(" -> ");
+            panic!("Import cycle: {}", chain);
+        }
+        if !self
+            .imported
+            .insert((canonical.clone(), namespace.map(String::from)))
+        {
+            // already imported, the groups and variables are there already
+            return Expanded::default();
+        }
 
-                    executable_path.push(l);
-                    executable_path.to_str()
-                        .unwrap_or_else(|| panic!("Path to import was not valid UTF-8: {:?}", executable_path))
-                        .to_string()
-                } else {
-                    l.to_string()
-                };
+        let (path, mut buffer) = match source {
+            Source::File(path) => {
                 let mut buffer = String::new();
-                File::open(dir_to_use)
-                    .unwrap_or_else(|_| panic!("Failed to open {} for importing", l))
+                File::open(&path)
+                    .unwrap_or_else(|_| panic!("Failed to open {:?} for importing", path))
                     .read_to_string(&mut buffer)
-                    .unwrap_or_else(|_| panic!("Failed to import file '{}'", l));
-                buffer
+                    .unwrap_or_else(|_| panic!("Failed to import file {:?}", path));
+                (path, buffer)
             }
-        })
-        .collect::<Vec<String>>();
-
-    let mut script: String = script_lines.join("\n");
+            Source::Bundled(content) => (canonical.clone(), content.to_string()),
+        };
+        if let Some(namespace) = namespace {
+            buffer = add_namespace(&buffer, namespace);
+        }
 
-    if script
-        .lines()
-        .any(|l| l.starts_with("&import(") && l.ends_with(')'))
-    {
-        script = perform_imports_inner(script);
+        self.chain.push(canonical);
+        let expanded = self.expand(&buffer, &path);
+        self.chain.pop();
+        expanded
     }
-
-    script
 }
 
 pub fn perform_imports(script: String) -> PreprocessedScript {
-    let script = perform_imports_inner(script);
+    perform_imports_from(script, Path::new("build.lb"))
+}
+
+/// Imports are resolved relative to `file`, the script that contains them
+pub fn perform_imports_from(script: String, file: &Path) -> PreprocessedScript {
+    let mut importer = Importer::default();
+    let root = std::fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+    importer.imported.insert((root.clone(), None));
+    importer.chain.push(root);
+    let expanded = importer.expand(&script, file);
+    expanded
+        .init
+        .into_iter()
+        .chain(expanded.body)
+        .collect::<Vec<String>>()
+        .join("\n")
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    #[test]
+    pub fn imports_are_relative_to_the_importing_file_and_only_done_once() {
+        let dir = ScratchDir::new("import-relative");
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("lib/a.lb"), "&import(b.lb)\n$a = 1").unwrap();
+        std::fs::write(dir.join("lib/b.lb"), "$b = 2").unwrap();
+        let script = "&import(lib/a.lb)\n&import(lib/b.lb)\n$c = 3".to_string();
 
-    script.into()
+        let script = perform_imports_from(script, &dir.join("build.lb")).script;
+        assert_eq!(script, "$b = 2\n$a = 1\n\n$c = 3");
+    }
+
+    #[test]
+    pub fn namespaced_imports_prefix_their_groups() {
+        let dir = ScratchDir::new("import-namespace");
+        std::fs::write(
+            dir.join("lib.lb"),
+            "&import(util.lb)\n[build $a\n    :l $a\n]\n@build\n!build 1\n!other 2",
+        )
+        .unwrap();
+        std::fs::write(dir.join("util.lb"), "[other $a\n]").unwrap();
+        let script = "&import(lib.lb as lib)\n!lib.build 3".to_string();
+
+        let script = perform_imports_from(script, &dir.join("build.lb")).script;
+        assert_eq!(
+            script,
+            "[other $a\n]\n[lib.build $a\n    :l $a\n]\n@build\n!lib.build 1\n!other 2\n!lib.build 3"
+        );
+    }
+
+    #[test]
+    pub fn override_marker_is_kept_when_adding_a_namespace() {
+        let script = add_namespace("[override over $a\n]\n  !over\n!overs", "ns");
+        assert_eq!(script, "[override ns.over $a\n]\n  !ns.over\n!overs");
+    }
+
+    #[test]
+    pub fn groups_run_by_each_get_the_namespace() {
+        let script = add_namespace("[member $m\n]\n:each member $members\n:each other $a", "ns");
+        assert_eq!(
+            script,
+            "[ns.member $m\n]\n:each ns.member $members\n:each other $a"
+        );
+    }
+
+    #[test]
+    pub fn override_replaces_the_earlier_group() {
+        let script = "[ns.build $a\n:l 1\n]\n[override ns.build\n:l 2\n:l 3\n]\n";
+        let groups = run(Lexer::new(script.into(), true));
+        let group = groups.get("ns.build").unwrap();
+        assert!(group.overrides);
+        assert!(group.args.is_empty());
+        assert_eq!(group.commands.len(), 2);
+    }
+
+    #[test]
+    pub fn optional_imports_may_be_missing() {
+        let dir = ScratchDir::new("import-optional");
+        std::fs::write(dir.join("local.lb"), "$local = yes").unwrap();
+        let script = "&import?(missing.lb)\n&import?(local.lb)\n$a = 1".to_string();
+
+        let script = perform_imports_from(script, &dir.join("build.lb")).script;
+        assert_eq!(script, "\n$local = yes\n$a = 1");
+    }
+
+    #[test]
+    pub fn conditions_remove_the_branch_not_taken() {
+        let script = format!(
+            "&if-os({})\n$os = yes\n&if-env(LB_TEST_NOT_SET)\n&import(missing.lb)\n&else\n$env = no\n&endif\n&else\n$os = no\n&endif\n$after = 1",
+            std::env::consts::OS
+        );
+
+        let script = perform_imports_from(script, Path::new("build.lb")).script;
+        assert_eq!(script, "$os = yes\n$env = no\n$after = 1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Missing &endif")]
+    pub fn conditions_have_to_be_ended() {
+        perform_imports_from("&if-os(linux)\n".into(), Path::new("build.lb"));
+    }
+
+    #[test]
+    pub fn init_sections_run_first_with_the_importer_first() {
+        let dir = ScratchDir::new("import-init");
+        std::fs::write(
+            dir.join("lib.lb"),
+            "&init\n$type ?= clippy\n&endinit\n@lib\n:l $type",
+        )
+        .unwrap();
+        let script = "@build\n&import(lib.lb)\n&init\n$type = check\n&endinit\n:l done".to_string();
+
+        let script = perform_imports_from(script, &dir.join("build.lb")).script;
+        assert_eq!(
+            script,
+            "$type = check\n$type ?= clippy\n@build\n@lib\n:l $type\n:l done"
+        );
+    }
+
+    #[test]
+    pub fn import_cycles_show_the_chain() {
+        let dir = ScratchDir::new("import-cycle");
+        std::fs::write(dir.join("a.lb"), "&import(b.lb)").unwrap();
+        std::fs::write(dir.join("b.lb"), "&import(a.lb)").unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            perform_imports_from("&import(a.lb)".into(), &dir.join("build.lb"))
+        });
+        let message = *result.unwrap_err().downcast::<String>().unwrap();
+        let a = dir.join("a.lb").canonicalize().unwrap();
+        let b = dir.join("b.lb").canonicalize().unwrap();
+        assert_eq!(
+            message,
+            format!("Import cycle: {0} -> {1} -> {0}", a.display(), b.display())
+        );
+    }
 }
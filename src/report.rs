@@ -0,0 +1,454 @@
+/*
+ * localhost-build is an experimental build scripting language
+ * Copyright (C) 2021  Carl Erik Patrik Iwarson
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::json;
+use crate::table::Table;
+use std::time::{Duration, Instant};
+
+/// `lb --report junit=path.xml` or `json=path.json`
+#[derive(Debug, PartialEq)]
+pub enum ReportFormat {
+    Junit(String),
+    Json(String),
+}
+
+impl ReportFormat {
+    pub fn parse(input: &str) -> ReportFormat {
+        match input.split_once('=') {
+            Some(("junit", path)) if !path.is_empty() => ReportFormat::Junit(path.into()),
+            Some(("json", path)) if !path.is_empty() => ReportFormat::Json(path.into()),
+            _ => panic!(
+                "expected junit=<path> or json=<path> after --report, got '{}'",
+                input
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepKind {
+    Phase,
+    Group,
+    Command,
+    Timer,
+}
+
+impl StepKind {
+    fn name(&self) -> &'static str {
+        match self {
+            StepKind::Phase => "phase",
+            StepKind::Group => "group",
+            StepKind::Command => "command",
+            StepKind::Timer => "timer",
+        }
+    }
+}
+
+pub struct Step {
+    pub kind: StepKind,
+    pub name: String,
+    /// The phase the step ran in
+    pub phase: Option<String>,
+    started: Instant,
+    pub duration: Option<Duration>,
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    /// The command lb quit with while the step was running
+    pub quit: Option<String>,
+}
+
+impl Step {
+    fn failed(&self) -> bool {
+        self.exit_code.is_some_and(|code| code != 0)
+    }
+
+    fn failure_message(&self) -> String {
+        let code = self.exit_code.unwrap_or_default();
+        match &self.quit {
+            Some(command) => format!("quit with {}, exit code {}", command, code),
+            None => format!("exit code {}", code),
+        }
+    }
+
+    fn seconds(&self) -> String {
+        format!("{:.3}", self.duration.unwrap_or_default().as_secs_f64())
+    }
+}
+
+/// Every phase, group, process, file system command and `:timer` that ran, in the order
+/// they started
+#[derive(Default)]
+pub struct Report {
+    pub formats: Vec<ReportFormat>,
+    /// `lb --timings`
+    pub timings: bool,
+    pub steps: Vec<Step>,
+    /// The exit code of lb and the command it quit with, if it quit with an error
+    pub quit: Option<(i32, String)>,
+}
+
+impl Report {
+    pub fn is_recording(&self) -> bool {
+        !self.formats.is_empty() || self.timings
+    }
+
+    /// Returns the index to finish the step with
+    pub fn start(&mut self, kind: StepKind, name: &str, phase: Option<&String>) -> usize {
+        self.steps.push(Step {
+            kind,
+            name: name.into(),
+            phase: phase.cloned(),
+            started: Instant::now(),
+            duration: None,
+            exit_code: None,
+            stdout: String::new(),
+            stderr: String::new(),
+            quit: None,
+        });
+        self.steps.len() - 1
+    }
+
+    pub fn finish(&mut self, step: usize) {
+        let step = &mut self.steps[step];
+        if step.duration.is_none() {
+            step.duration = Some(step.started.elapsed());
+        }
+    }
+
+    pub fn finish_command(&mut self, step: usize, exit_code: i32, stdout: &str, stderr: &str) {
+        self.finish(step);
+        let step = &mut self.steps[step];
+        step.exit_code = Some(exit_code);
+        step.stdout = stdout.into();
+        step.stderr = stderr.into();
+    }
+
+    /// lb quits with an error, the phase, group and command that are still running fail with
+    /// it, or a step for `command` does if only the phase is running
+    pub fn quit(&mut self, exit_code: i32, command: &str, phase: Option<&String>) {
+        self.quit = Some((exit_code, command.into()));
+        let mut failed_case = false;
+        for step in self.steps.iter_mut().filter(|step| step.duration.is_none()) {
+            if !step.failed() {
+                step.exit_code = Some(exit_code);
+            }
+            step.quit = Some(command.into());
+            failed_case |= step.kind != StepKind::Phase;
+        }
+        if !failed_case {
+            let step = self.start(StepKind::Command, command, phase);
+            self.finish_command(step, exit_code, "", "");
+            self.steps[step].quit = Some(command.into());
+        }
+    }
+
+    pub fn add_timer(&mut self, name: &str, phase: Option<&String>, duration: Duration) {
+        let step = self.start(StepKind::Timer, name, phase);
+        self.steps[step].duration = Some(duration);
+    }
+
+    /// Writes the reports and prints the timings, steps that are still running (e.g. when
+    /// quitting) end now
+    pub fn write(&mut self) {
+        for step in 0..self.steps.len() {
+            self.finish(step);
+        }
+        if self.timings {
+            self.print_timings();
+        }
+        for format in &self.formats {
+            let (path, content) = match format {
+                ReportFormat::Junit(path) => (path, self.junit()),
+                ReportFormat::Json(path) => (path, self.json()),
+            };
+            std::fs::write(path, content)
+                .unwrap_or_else(|err| panic!("failed to write report {}:\n{}", path, err));
+        }
+    }
+
+    /// Phases, group calls, commands and timers, the slowest first
+    pub fn timings_table(&self) -> Table {
+        let mut steps = self.steps.iter().collect::<Vec<&Step>>();
+        steps.sort_by_key(|step| std::cmp::Reverse(step.duration));
+
+        let mut table = Table::new(2);
+        table.set_headers(vec!["Time".into(), "Kind".into(), "Step".into()]);
+        for step in steps {
+            table.add_row(vec![
+                format!("{}s", step.seconds()),
+                step.kind.name().into(),
+                step.name.clone(),
+            ]);
+        }
+        table
+    }
+
+    fn print_timings(&self) {
+        let table = self.timings_table();
+        if !table.rows.is_empty() {
+            println!();
+            table.print();
+        }
+    }
+
+    /// One test suite per phase with its groups and commands as test cases
+    pub fn junit(&self) -> String {
+        let mut suites: Vec<(String, Option<&Step>, Vec<&Step>)> = Vec::new();
+        for step in &self.steps {
+            let suite = if step.kind == StepKind::Phase {
+                None
+            } else {
+                suites
+                    .iter()
+                    .rposition(|(name, _, _)| Some(name) == step.phase.as_ref())
+            };
+            match (step.kind, suite) {
+                (StepKind::Phase, _) => suites.push((step.name.clone(), Some(step), Vec::new())),
+                (_, Some(suite)) => suites[suite].2.push(step),
+                (_, None) => suites.push((
+                    step.phase.clone().unwrap_or_else(|| "lb".into()),
+                    None,
+                    vec![step],
+                )),
+            }
+        }
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+        for (name, phase, cases) in suites {
+            let failures = cases.iter().filter(|case| case.failed()).count();
+            xml.push_str(&format!(
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{}\">\n",
+                xml_escape(&name),
+                cases.len(),
+                failures,
+                phase.map(Step::seconds).unwrap_or_else(|| "0.000".into())
+            ));
+            for case in cases {
+                xml.push_str(&format!(
+                    "    <testcase classname=\"{}\" name=\"{}\" time=\"{}\">\n",
+                    xml_escape(&name),
+                    xml_escape(&case.name),
+                    case.seconds()
+                ));
+                if case.failed() {
+                    xml.push_str(&format!(
+                        "      <failure message=\"{}\"/>\n",
+                        xml_escape(&case.failure_message())
+                    ));
+                }
+                if !case.stdout.is_empty() {
+                    xml.push_str(&format!(
+                        "      <system-out>{}</system-out>\n",
+                        xml_escape(&case.stdout)
+                    ));
+                }
+                if !case.stderr.is_empty() {
+                    xml.push_str(&format!(
+                        "      <system-err>{}</system-err>\n",
+                        xml_escape(&case.stderr)
+                    ));
+                }
+                xml.push_str("    </testcase>\n");
+            }
+            xml.push_str("  </testsuite>\n");
+        }
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+
+    pub fn json(&self) -> String {
+        let steps = self
+            .steps
+            .iter()
+            .map(|step| {
+                format!(
+                    "    {{\"kind\": {}, \"name\": {}, \"phase\": {}, \"duration\": {}, \"exit_code\": {}, \"quit\": {}, \"stdout\": {}, \"stderr\": {}}}",
+                    json::quote(step.kind.name()),
+                    json::quote(&step.name),
+                    step.phase.as_deref().map(json::quote).unwrap_or_else(|| "null".into()),
+                    step.seconds(),
+                    step.exit_code.map(|code| code.to_string()).unwrap_or_else(|| "null".into()),
+                    step.quit.as_deref().map(json::quote).unwrap_or_else(|| "null".into()),
+                    json::quote(&step.stdout),
+                    json::quote(&step.stderr)
+                )
+            })
+            .collect::<Vec<String>>();
+        let (exit_code, quit) = match &self.quit {
+            Some((code, command)) => (*code, json::quote(command)),
+            None => (0, "null".into()),
+        };
+        format!(
+            "{{\n  \"exit_code\": {},\n  \"quit\": {},\n  \"steps\": [\n{}\n  ]\n}}\n",
+            exit_code,
+            quit,
+            steps.join(",\n")
+        )
+    }
+}
+
+/// Also drops the control characters XML can't contain, e.g. from colored output
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Json;
+
+    fn report() -> Report {
+        let mut report = Report::default();
+        let phase = "@build".to_string();
+        let build = report.start(StepKind::Phase, &phase, None);
+        let group = report.start(StepKind::Group, "!build", Some(&phase));
+        let command = report.start(StepKind::Command, ":e cargo build", Some(&phase));
+        report.finish_command(command, 101, "", "error: <bad> & \u{1b}[31mworse\u{1b}[0m");
+        report.finish(group);
+        report.finish(build);
+        let command = report.start(StepKind::Command, ":e echo \"hi\"", None);
+        report.finish_command(command, 0, "hi\n", "");
+        report
+    }
+
+    #[test]
+    pub fn junit_has_a_suite_per_phase() {
+        let xml = report().junit();
+        let lines = xml.lines().map(str::trim).collect::<Vec<&str>>();
+        assert!(lines[2].starts_with(r#"<testsuite name="@build" tests="2" failures="1" time=""#));
+        assert!(lines[3].starts_with(r#"<testcase classname="@build" name="!build" time=""#));
+        assert_eq!(lines[4], "</testcase>");
+        assert!(lines[5].starts_with(r#"<testcase classname="@build" name=":e cargo build""#));
+        assert_eq!(lines[6], r#"<failure message="exit code 101"/>"#);
+        assert_eq!(
+            lines[7],
+            "<system-err>error: &lt;bad&gt; &amp; [31mworse[0m</system-err>"
+        );
+        assert!(lines[10].starts_with(r#"<testsuite name="lb" tests="1" failures="0""#));
+        assert!(lines[11].contains(r#"name=":e echo &quot;hi&quot;""#));
+        assert_eq!(lines.last(), Some(&"</testsuites>"));
+    }
+
+    #[test]
+    pub fn json_report_can_be_read_back() {
+        let json = Json::parse(&report().json()).unwrap();
+        assert_eq!(json.get("exit_code").and_then(Json::as_u64), Some(0));
+        assert_eq!(json.get("quit"), Some(&Json::Null));
+        let steps = json.get("steps").unwrap().as_array();
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[1].get("kind").and_then(Json::as_str), Some("group"));
+        assert_eq!(steps[1].get("exit_code"), Some(&Json::Null));
+        assert_eq!(steps[2].get("phase").and_then(Json::as_str), Some("@build"));
+        assert_eq!(steps[2].get("exit_code").and_then(Json::as_u64), Some(101));
+        assert_eq!(steps[3].get("phase"), Some(&Json::Null));
+        assert_eq!(steps[3].get("stdout").and_then(Json::as_str), Some("hi\n"));
+    }
+
+    #[test]
+    pub fn quitting_fails_the_running_steps() {
+        let mut report = Report::default();
+        let phase = "@build".to_string();
+        let build = report.start(StepKind::Phase, &phase, None);
+        let group = report.start(StepKind::Group, "!check", Some(&phase));
+        let command = report.start(StepKind::Command, ":e cargo check", Some(&phase));
+        report.finish_command(command, 0, "", "");
+        report.quit(1, ":qet", Some(&phase));
+        assert_eq!(report.steps[build].exit_code, Some(1));
+        assert_eq!(report.steps[group].exit_code, Some(1));
+        assert_eq!(report.steps[command].quit, None);
+
+        let xml = report.junit();
+        assert!(
+            xml.contains(r#"<testsuite name="@build" tests="2" failures="1""#),
+            "{}",
+            xml
+        );
+        assert!(xml.contains(r#"<failure message="quit with :qet, exit code 1"/>"#));
+        let json = Json::parse(&report.json()).unwrap();
+        assert_eq!(json.get("exit_code").and_then(Json::as_u64), Some(1));
+        assert_eq!(json.get("quit").and_then(Json::as_str), Some(":qet"));
+    }
+
+    #[test]
+    pub fn quitting_outside_of_a_group_adds_a_failed_step() {
+        let mut report = Report::default();
+        let phase = "@build".to_string();
+        report.start(StepKind::Phase, &phase, None);
+        report.quit(1, ":qe", Some(&phase));
+        assert_eq!(report.steps.len(), 2);
+        assert_eq!(report.steps[1].name, ":qe");
+        assert!(report.junit().contains(r#"tests="1" failures="1""#));
+    }
+
+    #[test]
+    pub fn timings_are_sorted_slowest_first() {
+        let mut report = report();
+        report.steps[0].duration = Some(Duration::from_millis(2500));
+        report.steps[1].duration = Some(Duration::from_millis(2100));
+        report.steps[2].duration = Some(Duration::from_millis(2000));
+        report.steps[3].duration = Some(Duration::from_millis(10));
+        report.add_timer("tests", None, Duration::from_millis(300));
+        let rows = report
+            .timings_table()
+            .rows
+            .iter()
+            .map(|row| {
+                row.cells
+                    .iter()
+                    .map(|cell| cell.content.clone())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(
+            rows,
+            [
+                "2.500s phase @build",
+                "2.100s group !build",
+                "2.000s command :e cargo build",
+                "0.300s timer tests",
+                "0.010s command :e echo \"hi\""
+            ]
+        );
+    }
+
+    #[test]
+    pub fn report_formats_are_parsed() {
+        assert_eq!(
+            ReportFormat::parse("junit=out/lb.xml"),
+            ReportFormat::Junit("out/lb.xml".into())
+        );
+        assert_eq!(
+            ReportFormat::parse("json=lb.json"),
+            ReportFormat::Json("lb.json".into())
+        );
+    }
+}
@@ -17,14 +17,13 @@
  */
 use crate::tuple;
 use std::borrow::BorrowMut;
-use std::collections::HashMap;
 use std::iter::FromIterator;
 
 tuple!(FirstRest(first: String, rest: String));
 
 /// Separates (by spaces and tabs) the first value from the rest of the string
 pub fn separate_first_value_from_rest(input: String, command: &str) -> FirstRest {
-    let mut parts = input.split(|c| c == ' ' || c == '\t');
+    let mut parts = input.split([' ', '\t']);
     let first = parts
         .borrow_mut()
         .take(1)
@@ -64,6 +63,36 @@ pub fn get_strings(input: String) -> Vec<String> {
     strings
 }
 
+/// Splits by spaces and tabs unless inside double quotes, backslashes are kept as is
+/// so that Windows paths don't have to be escaped
+pub fn get_path_strings(input: String) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut sb = Vec::new();
+    let mut in_string = false;
+    for c in input.chars() {
+        match c {
+            '"' => {
+                if in_string || !sb.is_empty() {
+                    strings.push(String::from_iter(&sb));
+                    sb.clear();
+                }
+                in_string = !in_string;
+            }
+            ' ' | '\t' | '\r' | '\n' if !in_string => {
+                if !sb.is_empty() {
+                    strings.push(String::from_iter(&sb));
+                    sb.clear();
+                }
+            }
+            c => sb.push(c),
+        }
+    }
+    if !sb.is_empty() {
+        strings.push(String::from_iter(&sb));
+    }
+    strings
+}
+
 pub fn get_line_strings(input: String) -> Vec<String> {
     let mut strings = Vec::new();
     let mut sb = Vec::new();
@@ -141,28 +170,6 @@ pub fn get_line_strings(input: String) -> Vec<String> {
     strings
 }
 
-pub fn get_cache_line((file, crc): (&String, &u32)) -> String {
-    let file: String = file.trim_matches('"').escape_debug().to_string();
-    format!(r#""{}" "{}""#, file, crc)
-}
-
-pub fn parse_cache(cache: String) -> HashMap<String, u32> {
-    HashMap::from_iter(
-        cache
-            .lines()
-            .map(String::from)
-            .map(|l| {
-                let mut strings: Vec<String> = get_line_strings(l)
-                    .into_iter()
-                    .filter(|s| !s.is_empty())
-                    .collect();
-                assert_eq!(strings.len(), 2);
-                (strings.remove(0), strings.remove(0).parse::<u32>().unwrap())
-            })
-            .collect::<Vec<(String, u32)>>(),
-    )
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -202,59 +209,14 @@ mod tests {
     }
 
     #[test]
-    pub fn get_cache_line_as_expected() {
-        let cache = get_cache_line((&"bin/test.txt".to_string(), &!0u32));
-        assert_eq!(cache, r#""bin/test.txt" "4294967295""#.to_string())
+    pub fn path_strings_keep_backslashes() {
+        let strings = get_path_strings(r#""C:\Program Files\x" src\main.rs"#.to_string());
+        assert_eq!(strings, [r#"C:\Program Files\x"#, r#"src\main.rs"#]);
     }
 
     #[test]
-    pub fn get_cache_line_into_get_line_strings_as_expected() {
-        let cache = get_cache_line((&"bin/test.txt".to_string(), &!0u32));
-        let strings = get_line_strings(cache);
-        assert_eq!(strings[0], "bin/test.txt");
-        assert_eq!(strings[1], "4294967295");
+    pub fn path_strings_split_unquoted_values() {
+        let strings = get_path_strings("src  Cargo.toml\t\"build.lb\"".to_string());
+        assert_eq!(strings, ["src", "Cargo.toml", "build.lb"]);
     }
-
-    macro_rules! test_cache (
-        ($name:ident, $cache:expr, $expected_str:expr, $expected_hash:expr) => {
-            #[test]
-            pub fn $name() {
-                let line = get_cache_line($cache);
-                let parsed = parse_cache(line);
-                let hash = parsed.get(&$expected_str.to_string()).unwrap();
-                assert_eq!(hash, &$expected_hash);
-            }
-        }
-    );
-
-    test_cache!(
-        cache_backslashes,
-        (&r#""\\\\?\\C:\\Program Dreams""#.to_string(), &2),
-        r#"\\\\?\\C:\\Program Dreams"#,
-        2
-    );
-    test_cache!(
-        cache_backslashes_and_apostrophe,
-        (&r#""\\\\?\\C:\\Program Dream's""#.to_string(), &3),
-        r#"\\\\?\\C:\\Program Dream's"#,
-        3
-    );
-    test_cache!(
-        cache_no_extra_backslashes,
-        (&r#""\\?\C:\Program Dream\Subdir""#.to_string(), &4),
-        r#"\\?\C:\Program Dream\Subdir"#,
-        4
-    );
-    test_cache!(
-        cache_forward_slashes,
-        (&r#""C:/Program Size (x51)/qemu/file""#.to_string(), &5),
-        r#"C:/Program Size (x51)/qemu/file"#,
-        5
-    );
-    test_cache!(
-        cache_unix,
-        (&r#""/home/someone/thing""#.to_string(), &6),
-        r#"/home/someone/thing"#,
-        6
-    );
 }
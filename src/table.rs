@@ -63,7 +63,7 @@ impl Table {
         // print formatted table
         // TODO: do this but more readable at some point
         let header_count: usize = self.headers.len();
-        let header_max_sizes: Vec<usize> = self.headers.iter().cloned().map(|h| h.length).collect();
+        let header_max_sizes: Vec<usize> = self.headers.iter().map(|h| h.length).collect();
 
         let mut cell_max_sizes: Vec<usize> = Vec::new();
 
@@ -82,33 +82,52 @@ impl Table {
             max_sizes_all.push(usize::max(header_max_sizes[i], cell_max_sizes[i]));
         }
 
-        for i in 0..header_count {
+        for (header, max_size) in self.headers.iter().zip(&max_sizes_all) {
             print!(
                 "{}{}",
-                &self.headers[i].content,
-                " ".repeat(max_sizes_all[i] - self.headers[i].length + self.cell_margin)
+                &header.content,
+                " ".repeat(max_size - header.length + self.cell_margin)
             );
         }
         println!();
 
-        for i in 0..header_count {
+        for (header, max_size) in self.headers.iter().zip(&max_sizes_all) {
             print!(
                 "{}{}",
-                "-".repeat(self.headers[i].length),
-                " ".repeat(max_sizes_all[i] - self.headers[i].length + self.cell_margin)
+                "-".repeat(header.length),
+                " ".repeat(max_size - header.length + self.cell_margin)
             );
         }
         println!();
 
         for row in &self.rows {
-            for i in 0..header_count {
+            for (i, max_size) in max_sizes_all.iter().enumerate() {
                 print!(
                     "{}{}",
                     row.cells[i].content,
-                    " ".repeat(max_sizes_all[i] - row.cells[i].length + self.cell_margin)
+                    " ".repeat(max_size - row.cells[i].length + self.cell_margin)
                 );
             }
             println!();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    pub fn rows_shorter_than_the_headers_are_rejected() {
+        let mut table = Table::new(2);
+        table.set_headers(vec!["Level".into(), "Location".into(), "Message".into()]);
+        table.add_row(vec![
+            "error".into(),
+            "src/main.rs:1:1".into(),
+            "oops".into(),
+        ]);
+        table.add_row(vec!["warning".into(), "src/lib.rs:2:2".into()]);
+        table.print();
+    }
+}
@@ -39,6 +39,8 @@ pub struct GroupDefinition {
     pub name: String,
     pub args: Vec<String>,
     pub commands: Vec<Token>,
+    /// Defined with `[override name ...]`
+    pub overrides: bool,
 }
 
 #[derive(Debug, PartialEq, Clone)]
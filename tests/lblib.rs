@@ -0,0 +1,492 @@
+/*
+ * localhost-build is an experimental build scripting language
+ * Copyright (C) 2021  Carl Erik Patrik Iwarson
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published
+ * by the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Runs the scripts in lblib/ against the projects in tests/fixtures with stand-in tools on PATH
+#![cfg(unix)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Logs how it was called to `$TOOL_LOG` and fails if `$TOOL_FAIL` is one of its arguments,
+/// or several of them in a row. Prints `$TOOL_OUTPUT` if it exists, or how it did
+const STAND_IN: &str = r#"#!/bin/sh
+echo "$(basename "$0") $*" >> "$TOOL_LOG"
+case " $* " in
+    *" $TOOL_FAIL "*)
+        if [ -n "$TOOL_FAIL" ]; then
+            echo "$1 failed" >&2
+            if [ -f "$TOOL_OUTPUT" ]; then
+                cat "$TOOL_OUTPUT"
+            else
+                echo "$1 failed"
+            fi
+            exit 1
+        fi
+        ;;
+esac
+if [ -f "$TOOL_OUTPUT" ]; then
+    cat "$TOOL_OUTPUT"
+else
+    echo "$1 ok"
+fi
+"#;
+
+struct Project {
+    dir: PathBuf,
+    bin: PathBuf,
+}
+
+struct Run {
+    stdout: String,
+    /// One line per call to a stand-in tool
+    calls: Vec<String>,
+}
+
+impl Project {
+    fn new(fixture: &str, name: &str, tools: &[&str]) -> Project {
+        let root = std::env::temp_dir().join(format!("lb-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("project");
+        let bin = root.join("bin");
+        copy_dir(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(fixture),
+            &dir,
+        );
+        let project = Project { dir, bin };
+        for tool in tools {
+            stand_in(&project.bin.join(tool));
+        }
+        project
+    }
+
+    fn write(&self, path: &str, content: &str) {
+        fs::write(self.dir.join(path), content).unwrap();
+    }
+
+    /// What the stand-in tools print instead of "<first argument> ok"
+    fn tool_output(&self, content: &str) {
+        fs::write(self.bin.join("output"), content).unwrap();
+    }
+
+    fn run(&self, args: &[&str], fail: &str) -> Run {
+        self.run_expecting(args, fail, true)
+    }
+
+    fn run_expecting(&self, args: &[&str], fail: &str, success: bool) -> Run {
+        let log = self.bin.join("calls.log");
+        let _ = fs::remove_file(&log);
+        let path = format!(
+            "{}:{}",
+            self.bin.display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        let output = Command::new(env!("CARGO_BIN_EXE_lb"))
+            .args(args)
+            .current_dir(&self.dir)
+            .env("PATH", path)
+            .env("HOME", &self.dir)
+            .env_remove("LB_PATH")
+            .env("TOOL_LOG", &log)
+            .env("TOOL_FAIL", fail)
+            .env("TOOL_OUTPUT", self.bin.join("output"))
+            .output()
+            .unwrap();
+        assert_eq!(
+            output.status.success(),
+            success,
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Run {
+            stdout: String::from_utf8(output.stdout).unwrap(),
+            calls: fs::read_to_string(&log)
+                .unwrap_or_default()
+                .lines()
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.dir.parent().unwrap());
+    }
+}
+
+fn stand_in(path: &Path) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, STAND_IN).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+fn copy_dir(source: &Path, target: &Path) {
+    fs::create_dir_all(target).unwrap();
+    for entry in fs::read_dir(source).unwrap() {
+        let entry = entry.unwrap();
+        let target = target.join(entry.file_name());
+        if entry.path().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+#[test]
+fn go_runs_format_build_test_check() {
+    let project = Project::new("go", "go-workflow", &["go"]);
+    let run = project.run(&[], "");
+    assert_eq!(
+        run.calls,
+        [
+            "go fmt ./...",
+            "go build ./...",
+            "go test ./...",
+            "go vet ./..."
+        ]
+    );
+    assert!(run.stdout.contains("> build succeeded"), "{}", run.stdout);
+    assert!(run.stdout.contains("> tests succeeded"), "{}", run.stdout);
+}
+
+#[test]
+fn go_arguments_select_a_single_step() {
+    let project = Project::new("go", "go-arguments", &["go"]);
+    assert_eq!(
+        project.run(&["-b", "-r"], "").calls,
+        ["go build -trimpath ./..."]
+    );
+    assert_eq!(project.run(&["-t"], "").calls, ["go test ./..."]);
+    assert_eq!(
+        project.run(&["-c", "--set", "check-type=fix"], "").calls,
+        ["go fix ./..."]
+    );
+}
+
+#[test]
+fn go_stops_when_the_build_fails() {
+    let project = Project::new("go", "go-build-fails", &["go"]);
+    let run = project.run(&[], "build");
+    assert_eq!(run.calls, ["go fmt ./...", "go build ./..."]);
+    assert!(
+        run.stdout.contains("> build failed:\nbuild failed"),
+        "{}",
+        run.stdout
+    );
+}
+
+#[test]
+fn go_help_lists_the_arguments() {
+    let project = Project::new("go", "go-help", &["go"]);
+    let run = project.run(&["-h"], "");
+    assert!(run.calls.is_empty());
+    assert!(run.stdout.contains("[Go build script]"), "{}", run.stdout);
+    assert!(
+        run.stdout.contains("release -r --release"),
+        "{}",
+        run.stdout
+    );
+}
+
+#[test]
+fn node_installs_only_when_the_dependencies_change() {
+    let project = Project::new("node", "node-workflow", &["npm"]);
+    let run = project.run(&[], "");
+    assert_eq!(
+        run.calls,
+        ["npm install", "npm run lint", "npm test", "npm run build"]
+    );
+    assert!(
+        run.stdout.contains("> dependencies installed with npm"),
+        "{}",
+        run.stdout
+    );
+
+    fs::create_dir(project.dir.join("node_modules")).unwrap();
+    assert_eq!(
+        project.run(&[], "").calls,
+        ["npm run lint", "npm test", "npm run build"]
+    );
+    project.write("package.json", "{}");
+    assert_eq!(project.run(&["-t"], "").calls, ["npm install", "npm test"]);
+}
+
+#[test]
+fn node_package_manager_is_picked_from_the_lockfile() {
+    let project = Project::new("node", "node-lockfile", &["npm", "pnpm", "yarn"]);
+    fs::remove_file(project.dir.join("package-lock.json")).unwrap();
+    project.write("yarn.lock", "");
+    assert_eq!(
+        project.run(&["-l"], "").calls,
+        ["yarn install", "yarn run lint"]
+    );
+
+    project.write("pnpm-lock.yaml", "");
+    assert_eq!(
+        project.run(&["-b"], "").calls,
+        ["pnpm install", "pnpm run build"]
+    );
+
+    assert_eq!(
+        project
+            .run(&["-i", "--set", "package-manager=npm"], "")
+            .calls,
+        ["npm install"]
+    );
+}
+
+#[test]
+fn node_stops_when_linting_fails() {
+    let project = Project::new("node", "node-lint-fails", &["npm"]);
+    let run = project.run(&[], "run");
+    assert_eq!(run.calls, ["npm install", "npm run lint"]);
+    assert!(run.stdout.contains("> linting failed"), "{}", run.stdout);
+}
+
+#[test]
+fn python_creates_a_venv_and_runs_the_tools_from_it() {
+    let project = Project::new("python", "python-workflow", &["python3"]);
+    // what python3 -m venv would have created
+    stand_in(&project.dir.join(".venv/bin/python"));
+    let run = project.run(&[], "");
+    assert_eq!(
+        run.calls,
+        [
+            "python3 -m venv .venv",
+            "python -m pip install -r requirements.txt",
+            "python -m ruff format .",
+            "python -m pytest",
+            "python -m ruff check ."
+        ]
+    );
+
+    assert_eq!(
+        project.run(&["-f", "--set", "format-with=black"], "").calls,
+        ["python -m black ."]
+    );
+}
+
+#[test]
+fn python_stops_when_the_venv_cant_be_created() {
+    let project = Project::new("python", "python-no-venv", &["python3"]);
+    let run = project.run(&["-t"], "-m");
+    assert_eq!(run.calls, ["python3 -m venv .venv"]);
+    assert!(
+        run.stdout.contains("> creating .venv failed"),
+        "{}",
+        run.stdout
+    );
+}
+
+#[test]
+fn python_help_lists_the_arguments() {
+    let project = Project::new("python", "python-help", &["python3"]);
+    let run = project.run(&["--help"], "");
+    assert!(run.calls.is_empty());
+    assert!(
+        run.stdout.contains("[Python build script]"),
+        "{}",
+        run.stdout
+    );
+    assert!(run.stdout.contains("ruff check"), "{}", run.stdout);
+}
+
+#[test]
+fn rust_builds_the_whole_workspace_or_one_package() {
+    let project = Project::new("rust-workspace", "rust-workspace", &["cargo"]);
+    let run = project.run(&["--set", "color="], "");
+    assert_eq!(
+        run.calls,
+        [
+            "cargo fmt",
+            "cargo build --workspace --message-format=json",
+            "cargo test --workspace --",
+            "cargo clippy --workspace --message-format=json"
+        ]
+    );
+
+    assert_eq!(
+        project
+            .run(
+                &["-b", "-p", "app-core", "--all-features", "--set", "color="],
+                ""
+            )
+            .calls,
+        ["cargo build -p app-core --all-features --message-format=json"]
+    );
+    assert_eq!(
+        project
+            .run(
+                &["-t", "--set", "features=serde,cli", "--set", "color="],
+                ""
+            )
+            .calls,
+        ["cargo test --workspace --features serde,cli --"]
+    );
+}
+
+#[test]
+fn rust_tests_each_member_into_a_table() {
+    let project = Project::new("rust-workspace", "rust-members", &["cargo"]);
+    let run = project.run(&["-m", "--set", "color="], "-p app-cli");
+    assert_eq!(
+        run.calls,
+        ["cargo test -p app-cli --", "cargo test -p app-core --"]
+    );
+    let table = run
+        .stdout
+        .lines()
+        .skip_while(|line| !line.contains("Package"))
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .collect::<Vec<String>>();
+    assert!(
+        table.contains(&"app-core ok 0 0 0".to_string()),
+        "{}",
+        run.stdout
+    );
+    assert!(
+        table.contains(&"app-cli failed 0 0 0".to_string()),
+        "{}",
+        run.stdout
+    );
+}
+
+#[test]
+fn rust_members_are_tested_when_imported_with_a_namespace() {
+    let project = Project::new("rust-workspace", "rust-members-namespace", &["cargo"]);
+    project.write("build.lb", "&import(lblib/rust.lb as rust)\n");
+    let run = project.run(&["-m", "--set", "color="], "");
+    assert_eq!(
+        run.calls,
+        ["cargo test -p app-cli --", "cargo test -p app-core --"]
+    );
+}
+
+#[test]
+fn rust_warnings_are_counted_and_can_fail_the_build() {
+    let project = Project::new("rust-workspace", "rust-warnings", &["cargo"]);
+    project.tool_output(concat!(
+        r#"{"reason":"compiler-message","message":{"level":"warning","message":"unused variable: `x`","spans":[{"file_name":"crates/cli/src/main.rs","is_primary":true,"line_start":2,"column_start":9}]}}"#,
+        "\n",
+        r#"{"reason":"build-finished","success":true}"#,
+    ));
+    let run = project.run(&["-b", "--set", "color="], "");
+    assert!(
+        run.stdout.contains("> build succeeded with 1 warnings"),
+        "{}",
+        run.stdout
+    );
+    assert!(
+        run.stdout.contains("crates/cli/src/main.rs:2:9"),
+        "{}",
+        run.stdout
+    );
+
+    let run = project.run_expecting(
+        &["-b", "--set", "color=", "--set", "deny-warnings=yes"],
+        "",
+        false,
+    );
+    assert!(
+        run.stdout.contains("> failing because of 1 warnings"),
+        "{}",
+        run.stdout
+    );
+}
+
+#[test]
+fn rust_test_failures_are_summarized() {
+    let project = Project::new("rust-workspace", "rust-test-summary", &["cargo"]);
+    project.tool_output(
+        "running 3 tests
+test tests::adds ... ok
+test tests::subtracts ... FAILED
+test tests::slow ... ignored
+
+failures:
+
+---- tests::subtracts stdout ----
+assertion failed
+
+test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.01s
+",
+    );
+    let run = project.run(&["-t", "--set", "color="], "test");
+    let summary = run
+        .stdout
+        .lines()
+        .skip_while(|line| !line.starts_with("Failed test"))
+        .map(str::trim_end)
+        .collect::<Vec<&str>>();
+    assert_eq!(
+        summary,
+        [
+            "Failed test",
+            "-----------",
+            "tests::subtracts",
+            "> 1 failed, 1 passed, 1 ignored"
+        ]
+    );
+}
+
+#[test]
+fn reports_are_written_when_quitting_early() {
+    let project = Project::new("rust-workspace", "rust-report", &["cargo"]);
+    project.tool_output(
+        r#"{"reason":"compiler-message","message":{"level":"warning","message":"unused","spans":[]}}"#,
+    );
+    let junit = project.dir.join("lb.xml");
+    let json = project.dir.join("lb.json");
+    project.run_expecting(
+        &[
+            "-b",
+            "--set",
+            "deny-warnings=yes",
+            "--report",
+            &format!("junit={}", junit.display()),
+            "--report",
+            &format!("json={}", json.display()),
+        ],
+        "",
+        false,
+    );
+
+    let junit = fs::read_to_string(junit).unwrap();
+    assert!(
+        junit.contains(r#"<testsuite name="@build-only" tests="3" failures="1""#),
+        "{}",
+        junit
+    );
+    assert!(junit.contains(r#"name="!fail-on-warnings""#), "{}", junit);
+    assert!(
+        junit.contains(r#"<failure message="quit with :qet, exit code 1"/>"#),
+        "{}",
+        junit
+    );
+    let json = fs::read_to_string(json).unwrap();
+    assert!(json.contains(r#""exit_code": 1,"#), "{}", json);
+    assert!(
+        json.contains(r#""kind": "command", "name": ":e cargo build --workspace --message-format=json --color=always", "phase": "@build-only""#),
+        "{}",
+        json
+    );
+}